@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+
+pub const BACKUP_DIR_NAME: &str = "re0box_backups";
+const BACKUP_EXTENSION: &str = "bin";
+/// Added to the name of a backup once it's been restored, so it isn't restored again
+const RESTORED_EXTENSION: &str = "restored";
+
+/// A folder of the most recent copies of the box data, one written each time the game saves. Backups
+/// that are already there can be read even if we aren't keeping any more.
+#[derive(Debug)]
+pub struct Backups {
+    dir: PathBuf,
+    count: usize,
+}
+
+impl Backups {
+    pub const fn new(dir: PathBuf, count: usize) -> Self {
+        Self { dir, count }
+    }
+
+    /// Whether we write new backups
+    pub const fn is_enabled(&self) -> bool {
+        self.count > 0
+    }
+
+    fn file_name(timestamp: u128, slot: usize) -> String {
+        // the timestamp comes first and is zero-padded so backups sort oldest first by name
+        format!("{:016}_slot{:02}.{}", timestamp, slot, BACKUP_EXTENSION)
+    }
+
+    /// Write a backup of the box data saved from the given slot and delete the oldest backups
+    /// beyond the number we're supposed to keep
+    pub fn write(&self, slot: usize, data: &[u8]) -> Result<PathBuf> {
+        std::fs::create_dir_all(&self.dir)?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let mut path = self.dir.join(Self::file_name(timestamp, slot));
+        // saving twice in the same millisecond shouldn't be possible, but just in case
+        let mut timestamp = timestamp;
+        while path.exists() {
+            timestamp += 1;
+            path = self.dir.join(Self::file_name(timestamp, slot));
+        }
+        std::fs::write(&path, data)?;
+
+        let backups = self.list()?;
+        if backups.len() > self.count {
+            for old in &backups[..backups.len() - self.count] {
+                std::fs::remove_file(old)?;
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// All backups, oldest first
+    pub fn list(&self) -> Result<Vec<PathBuf>> {
+        let mut backups = Vec::new();
+        if !self.dir.exists() {
+            return Ok(backups);
+        }
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.is_file() && path.extension().is_some_and(|e| e == BACKUP_EXTENSION) {
+                backups.push(path);
+            }
+        }
+        backups.sort();
+        Ok(backups)
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        // only allow files from the backup folder
+        let file_name = Path::new(name).file_name();
+        if file_name.is_none() || file_name != Some(name.as_ref()) {
+            bail!("{:?} is not the name of a backup file", name);
+        }
+        Ok(self.dir.join(name))
+    }
+
+    fn restored_path(&self, name: &str) -> Result<PathBuf> {
+        let mut path = self.path(name)?.into_os_string();
+        path.push(".");
+        path.push(RESTORED_EXTENSION);
+        Ok(path.into())
+    }
+
+    /// Read the backup with the given file name
+    pub fn read(&self, name: &str) -> Result<Vec<u8>> {
+        Ok(std::fs::read(self.path(name)?)?)
+    }
+
+    /// Rename a backup so it isn't restored again
+    pub fn mark_restored(&self, name: &str) -> Result<()> {
+        std::fs::rename(self.path(name)?, self.restored_path(name)?)?;
+        Ok(())
+    }
+
+    /// Whether the backup with the given file name has already been restored
+    pub fn is_restored(&self, name: &str) -> bool {
+        self.restored_path(name).is_ok_and(|path| path.exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_backups() {
+        let dir = std::env::temp_dir().join(format!("re0box-backups-{}", std::process::id()));
+        let backups = Backups::new(dir.clone(), 3);
+        assert!(backups.list().unwrap().is_empty());
+
+        let mut paths = Vec::new();
+        for i in 0..5u8 {
+            paths.push(backups.write(i as usize, &[i]).unwrap());
+        }
+        // only the newest three are kept
+        assert_eq!(backups.list().unwrap(), paths[2..]);
+
+        let name = paths[3].file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("_slot03.bin"), "{}", name);
+        assert_eq!(backups.read(name).unwrap(), [3]);
+        assert!(backups.read("../re0box.sav").is_err());
+
+        // a restored backup is no longer listed or readable by its old name
+        assert!(!backups.is_restored(name));
+        backups.mark_restored(name).unwrap();
+        assert!(backups.is_restored(name));
+        assert!(backups.read(name).is_err());
+        assert_eq!(backups.list().unwrap().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+//! Replays the box operations from a trace-level re0box.log and reports where the box diverged
+//! from what was logged
+use std::process::ExitCode;
+
+use anyhow::{Context, Result};
+
+fn run() -> Result<bool> {
+    let path = std::env::args()
+        .nth(1)
+        .context("Usage: re0box-replay <path to re0box.log>")?;
+    // logs can contain non-UTF-8 garbage if the game crashed mid-write
+    let log = String::from_utf8_lossy(
+        &std::fs::read(&path).with_context(|| format!("Failed to read {}", path))?,
+    )
+    .into_owned();
+    let report = re0box::replay::replay(&log)?;
+    println!("{}", report);
+    Ok(report.divergence.is_none())
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{:?}", e);
+            ExitCode::from(2)
+        }
+    }
+}
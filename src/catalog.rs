@@ -0,0 +1,53 @@
+// names of the items we know about. this is used to make box contents readable in text form, so
+// it doesn't have to be complete; anything missing from here is just referred to by its ID.
+const ITEM_NAMES: [(i32, &str); 8] = [
+    (5, "Hunting Gun"),
+    (6, "Shotgun"),
+    (7, "Grenade Launcher (Grenade Rounds)"),
+    (8, "Grenade Launcher (Flame Rounds)"),
+    (9, "Grenade Launcher (Acid Rounds)"),
+    (11, "Sub-Machine Gun"),
+    (23, "Rocket Launcher"),
+    (104, "Hookshot"),
+];
+
+// items needed to progress. like the names, this isn't complete, so the KeyItems option can add
+// the rest.
+#[cfg(any(windows, test))]
+const KEY_ITEMS: [i32; 1] = [104];
+
+#[cfg(any(windows, test))]
+pub fn is_key_item(id: i32) -> bool {
+    KEY_ITEMS.contains(&id)
+}
+
+pub fn item_name(id: i32) -> Option<&'static str> {
+    ITEM_NAMES
+        .iter()
+        .find(|(item_id, _)| *item_id == id)
+        .map(|(_, name)| *name)
+}
+
+pub fn item_id(name: &str) -> Option<i32> {
+    let name = name.trim();
+    ITEM_NAMES
+        .iter()
+        .find(|(_, item_name)| item_name.eq_ignore_ascii_case(name))
+        .map(|(id, _)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        assert_eq!(item_name(6), Some("Shotgun"));
+        assert_eq!(item_name(55), None);
+        assert_eq!(item_id("shotgun"), Some(6));
+        assert_eq!(item_id(" Sub-Machine Gun "), Some(11));
+        assert_eq!(item_id("not an item"), None);
+        assert!(is_key_item(104));
+        assert!(!is_key_item(6));
+    }
+}
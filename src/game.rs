@@ -1,10 +1,8 @@
 use std::arch::asm;
-use std::ffi::c_void;
-use std::io::Cursor;
+use std::ffi::{c_void, CStr};
 use std::path::PathBuf;
 
 use anyhow::{anyhow, bail, Result};
-use binrw::{binrw, BinReaderExt, BinWrite};
 use windows::core::PWSTR;
 use windows::Win32::Foundation::MAX_PATH;
 use windows::Win32::System::Memory::PAGE_READONLY;
@@ -12,8 +10,9 @@ use windows::Win32::System::Threading::{
     GetCurrentProcess, QueryFullProcessImageNameW, PROCESS_NAME_FORMAT,
 };
 
-use super::inventory::{Bag, Item};
+use super::inventory::{Bag, EquippedItem, Item};
 use super::patch::ByteSearcher;
+use super::storage::{SaveBackend, SaveManager, SaveSettings};
 
 #[derive(Debug)]
 pub struct GameVersion {
@@ -60,6 +59,8 @@ pub struct GameVersion {
     pub msg_load3: usize,
     pub shaft_check: usize,
     pub new_game: usize,
+    /// Size of the game's own save file, without our box data
+    pub save_size: usize,
 }
 
 impl GameVersion {
@@ -114,6 +115,7 @@ pub const SUPPORTED_VERSIONS: [GameVersion; 2] = [
         msg_load3: 0x005D67E1,
         shaft_check: 0x005E3D73,
         new_game: 0x0041249C,
+        save_size: 0x0023A8F0,
     },
     GameVersion {
         version_string: b"MasterRelease Jan 28 2025 16:45:59\0",
@@ -159,38 +161,22 @@ pub const SUPPORTED_VERSIONS: [GameVersion; 2] = [
         msg_load3: 0x005D6B61,
         shaft_check: 0x005E40E3,
         new_game: 0x0041240C,
+        save_size: 0x0023A8F0,
     },
 ];
 
 pub const MOVE_SELECTION_SOUND: i32 = 2050;
 pub const FAIL_SOUND: i32 = 2053;
-pub const NUM_SAVE_SLOTS: usize = 20;
-pub const MAGIC: &[u8] = b"IBOX";
-pub const UNMODDED_SAVE_SIZE: usize = 2337008; // this is the size of the 20 save slots plus, presumably, a few hundred bytes of header/metadata
-
-#[binrw]
-#[derive(Debug, Default)]
-struct ItemVec {
-    #[bw(calc = items.len() as u32)]
-    count: u32,
-    #[br(count = count)]
-    items: Vec<Item>,
-}
-
-impl ItemVec {
-    pub const fn new() -> Self {
-        Self { items: Vec::new() }
-    }
-}
 
 /// Game API and state information
 #[derive(Debug)]
 pub struct Game {
     pub user_had_ink_ribbon: bool,
     pub should_open_box: bool,
-    is_mod_enabled: bool,
     box_partner: *const c_void,
     original_exchange_state: i8,
+    player_bag: *mut Bag,
+    equipped_item: Option<EquippedItem>,
     draw_bags: Option<unsafe extern "fastcall" fn(*const c_void) -> *mut Bag>,
     get_character_bag: Option<unsafe extern "fastcall" fn(*const c_void) -> *mut Bag>,
     get_partner_character: Option<unsafe extern "fastcall" fn(*const c_void) -> *const c_void>,
@@ -201,7 +187,7 @@ pub struct Game {
     get_remote_storage: *const unsafe extern "C" fn() -> *const *const usize,
     ptr_dcdf3c: *const *const c_void,
     ptr_dd0bd0: *const *const c_void,
-    saved_boxes: [ItemVec; NUM_SAVE_SLOTS],
+    saves: SaveManager,
     current_version: Option<&'static GameVersion>,
 }
 
@@ -210,9 +196,10 @@ impl Game {
         Self {
             user_had_ink_ribbon: false,
             should_open_box: false,
-            is_mod_enabled: true,
             box_partner: std::ptr::null(),
             original_exchange_state: 0,
+            player_bag: std::ptr::null_mut(),
+            equipped_item: None,
             draw_bags: None,
             get_character_bag: None,
             get_partner_character: None,
@@ -223,33 +210,12 @@ impl Game {
             get_remote_storage: std::ptr::null(),
             ptr_dd0bd0: std::ptr::null(),
             ptr_dcdf3c: std::ptr::null(),
-            saved_boxes: [
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-                ItemVec::new(),
-            ],
+            saves: SaveManager::new(),
             current_version: None,
         }
     }
 
-    pub unsafe fn init(&mut self, is_mod_enabled: bool) -> Result<()> {
+    pub unsafe fn init(&mut self, is_mod_enabled: bool, settings: SaveSettings) -> Result<()> {
         // identify the current game version
         let mut searcher = ByteSearcher::new();
         searcher.discover_modules()?;
@@ -267,7 +233,7 @@ impl Game {
             bail!("Unsupported or unknown game version");
         };
         
-        self.is_mod_enabled = is_mod_enabled;
+        self.saves.init(is_mod_enabled, version.save_size, settings, Self::get_game_dir());
         self.draw_bags = Some(std::mem::transmute(version.draw_bags));
         self.get_character_bag = Some(std::mem::transmute(version.get_character_bag));
         self.get_partner_character = Some(std::mem::transmute(version.get_partner_character));
@@ -306,6 +272,46 @@ impl Game {
         };
     }
 
+    pub unsafe fn track_player_bag(&mut self, bag: *mut Bag) {
+        // remember what's equipped when we first see the bag so we can tell if an exchange moves it
+        if bag != self.player_bag {
+            self.player_bag = bag;
+            self.equipped_item = bag.as_ref().and_then(Bag::equipped_item);
+        }
+    }
+
+    pub unsafe fn fix_equipped_item(&mut self) {
+        if let Some(bag) = self.player_bag.as_mut() {
+            bag.fix_equipped_item(self.equipped_item.as_ref());
+            self.equipped_item = bag.equipped_item();
+        }
+    }
+
+    /// Whether `undo_exchange` would be able to give the personal item back to the player
+    pub unsafe fn can_undo_exchange(&self, item: &Item, received: &Item) -> bool {
+        self.player_bag
+            .as_ref()
+            .is_some_and(|bag| bag.can_undo_exchange(item, received))
+    }
+
+    /// Give a personal item back to the player in place of the box item they got for it. If
+    /// that's not possible, the item is returned.
+    pub unsafe fn undo_exchange(&mut self, item: Item, received: &Item) -> Result<(), Item> {
+        match self.player_bag.as_mut() {
+            Some(bag) => bag.undo_exchange(item, received),
+            None => Err(item),
+        }
+    }
+
+    pub fn has_player_bag(&self) -> bool {
+        !self.player_bag.is_null()
+    }
+
+    pub fn forget_player_bag(&mut self) {
+        self.player_bag = std::ptr::null_mut();
+        self.equipped_item = None;
+    }
+
     pub unsafe fn draw_bags(&self, unknown: *const c_void) -> *mut Bag {
         self.draw_bags.unwrap()(unknown)
     }
@@ -339,82 +345,44 @@ impl Game {
     }
 
     pub fn save_to_slot(&mut self, items: &[Item], index: usize) {
-        self.saved_boxes[index].items = Vec::from(if self.is_mod_enabled {
-            items
-        } else {
-            // if the mod is disabled, clear the box in this slot
-            &[]
-        });
+        self.saves.save_to_slot(items, index);
     }
 
-    pub fn save(&self, game_buf: &[u8], filename: *const u8) -> Result<()> {
-        let buf = Vec::with_capacity(
-            game_buf.len()
-                + MAGIC.len()
-                + NUM_SAVE_SLOTS * size_of::<u32>()
-                + self
-                    .saved_boxes
-                    .iter()
-                    .fold(0, |a, b| a + b.items.len() * size_of::<Item>()),
-        );
-        let mut writer = Cursor::new(buf);
-        game_buf.write(&mut writer)?;
-        MAGIC.write(&mut writer)?;
-        self.saved_boxes.write_le(&mut writer)?;
-        let buf = writer.get_ref();
-
-        // pass our buffer to Steam with thiscall calling convention
-        let result: u8;
-        unsafe {
-            let remote_storage = self.get_remote_storage();
-
-            asm!(
-                "push {size}",
-                "push {buf}",
-                "push {name}",
-                "call {func}",
-                in("ecx") remote_storage,
-                func = in(reg) **remote_storage,
-                name = in(reg) filename,
-                buf = in(reg) buf.as_ptr(),
-                size = in(reg) buf.len(),
-                lateout("al") result,
-            );
-        }
-
-        match result {
-            0 => Err(anyhow!("Failed to save file")),
-            _ => Ok(()),
-        }
+    pub fn save(&mut self, game_buf: &[u8], filename: *const u8) -> Result<()> {
+        let name = unsafe { CStr::from_ptr(filename as *const _) };
+        let mut backend = SteamBackend {
+            remote_storage: unsafe { self.get_remote_storage() },
+            loaded: &[],
+        };
+        self.saves.save(game_buf, name, &mut backend)
     }
 
-    pub fn load_from_slot(&self, index: usize) -> Vec<Item> {
-        self.saved_boxes[index].items.clone()
+    pub fn load_from_slot(&mut self, index: usize) -> Vec<Item> {
+        self.saves.load_from_slot(index)
     }
 
-    pub fn clear_save(&mut self) {
-        for slot in &mut self.saved_boxes {
-            slot.items.clear();
-        }
+    /// The box to start a new game with, which depends on the box scope
+    pub fn new_game_box(&mut self) -> Vec<Item> {
+        self.saves.new_game_box()
     }
 
     pub fn load(&mut self, buf: &[u8]) -> Result<()> {
-        if buf.len() <= UNMODDED_SAVE_SIZE {
-            // this is the first time the mod has been used. clear out the boxes.
-            self.clear_save();
-            return Ok(());
-        }
+        let mut backend = SteamBackend {
+            remote_storage: unsafe { self.get_remote_storage() },
+            loaded: buf,
+        };
+        // the game doesn't tell us the name of the file it loaded, but the backend doesn't need it
+        self.saves.load(c"", &mut backend)
+    }
 
-        let mut reader = Cursor::new(&buf[UNMODDED_SAVE_SIZE..]);
-        if reader.read_le::<[u8; 4]>()? != MAGIC {
-            // something weird has happened
-            return Err(anyhow!(
-                "Save file appears to be modded but box data was not correct"
-            ));
-        }
-        self.saved_boxes = reader.read_le()?;
+    /// Whether the box can't be used because the box data in the save couldn't be read
+    pub fn is_box_locked(&self) -> bool {
+        self.saves.is_protected()
+    }
 
-        Ok(())
+    /// The size of the game's own save data in the save file the game last loaded
+    pub fn vanilla_size(&self) -> usize {
+        self.saves.vanilla_size()
     }
 
     pub unsafe fn get_game_dir() -> PathBuf {
@@ -432,3 +400,41 @@ impl Game {
             .and_then(|s| PathBuf::from(s).parent().map(PathBuf::from)).unwrap_or_else(|| PathBuf::from("../"))
     }
 }
+
+/// Saves through Steam's remote storage, the same way the game does
+struct SteamBackend<'a> {
+    remote_storage: *const *const usize,
+    // the game reads its save file from Steam itself before we ever see it, so reading just hands
+    // back what it read
+    loaded: &'a [u8],
+}
+
+impl SaveBackend for SteamBackend<'_> {
+    fn write(&mut self, name: &CStr, buf: &[u8]) -> Result<()> {
+        // pass our buffer to Steam with thiscall calling convention
+        let result: u8;
+        unsafe {
+            asm!(
+                "push {size}",
+                "push {buf}",
+                "push {name}",
+                "call {func}",
+                in("ecx") self.remote_storage,
+                func = in(reg) **self.remote_storage,
+                name = in(reg) name.as_ptr(),
+                buf = in(reg) buf.as_ptr(),
+                size = in(reg) buf.len(),
+                lateout("al") result,
+            );
+        }
+
+        match result {
+            0 => Err(anyhow!("Failed to save file")),
+            _ => Ok(()),
+        }
+    }
+
+    fn read(&mut self, _name: &CStr) -> Result<Vec<u8>> {
+        Ok(Vec::from(self.loaded))
+    }
+}
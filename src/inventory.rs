@@ -1,6 +1,15 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
 use binrw::binrw;
+use serde::{Deserialize, Serialize};
+
+use super::catalog;
 
 pub const BAG_SIZE: usize = 6;
+/// Start of the warning logged when the box keeps a personal item, which the replay looks for
+pub const KEEPING_PERSONAL_ITEM: &str = "Keeping personal item";
 const SLOT_TWO: i32 = 180;
 const TWO_SLOT_ITEMS: [i32; 9] = [
     5,   // hunting gun
@@ -22,11 +31,71 @@ pub struct Item {
     count: i32,
 }
 
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = if self.is_empty() {
+            String::from("<empty>")
+        } else if self.is_slot_two() {
+            String::from("<slot two>")
+        } else {
+            match catalog::item_name(self.id) {
+                Some(name) => format!("{} x{}", name, self.count),
+                None => format!("{} x{}", self.id, self.count),
+            }
+        };
+        // pad() so callers can line items up in columns
+        f.pad(&text)
+    }
+}
+
+impl FromStr for Item {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s {
+            "<empty>" => return Ok(Self::empty()),
+            "<slot two>" => return Ok(Self::slot_two()),
+            _ => (),
+        }
+
+        let (name, count) = s
+            .rsplit_once(" x")
+            .ok_or_else(|| anyhow!("Item {:?} is missing a count", s))?;
+        let id = match name.trim().parse() {
+            Ok(id) => id,
+            Err(_) => {
+                catalog::item_id(name).ok_or_else(|| anyhow!("Unknown item name {:?}", name))?
+            }
+        };
+        Ok(Self::new(id, count.parse()?))
+    }
+}
+
 impl Item {
+    pub const fn new(id: i32, count: i32) -> Self {
+        Self { id, count }
+    }
+
     pub const fn empty() -> Self {
         Self { id: 0, count: 0 }
     }
 
+    pub const fn slot_two() -> Self {
+        Self {
+            id: SLOT_TWO,
+            count: 1,
+        }
+    }
+
+    pub const fn id(&self) -> i32 {
+        self.id
+    }
+
+    pub const fn count(&self) -> i32 {
+        self.count
+    }
+
     pub const fn is_empty(&self) -> bool {
         self.id == 0
     }
@@ -40,7 +109,15 @@ impl Item {
     }
 }
 
-#[derive(Debug, Default)]
+/// The item a character has equipped, remembered so the equipped index can be fixed up after
+/// items in their bag get moved around
+#[derive(Debug, Clone, PartialEq)]
+pub struct EquippedItem {
+    index: usize,
+    item: Item,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
 #[repr(C)]
 pub struct Bag {
     unknown00: i32,
@@ -80,7 +157,7 @@ impl Bag {
             // if there's a two-slot item not followed by SLOT_TWO, or a SLOT_TWO preceded by an
             // item that's not two slots, the view is in a broken state
             let is_two_slot_item = item.is_two_slot_item();
-            let slot_two_follows = self.items.get(i + 1).map_or(false, Item::is_slot_two);
+            let slot_two_follows = self.items.get(i + 1).is_some_and(Item::is_slot_two);
             if is_two_slot_item != slot_two_follows {
                 log::trace!(
                     "View is broken at {}: i is_two_slot_item = {}, i + 1 is_slot_two = {}",
@@ -98,6 +175,11 @@ impl Bag {
         self.is_organized() && !self.is_broken()
     }
 
+    /// Everything wrong with the layout of the bag. The bag is valid if this is empty.
+    pub fn check_invariants(&self) -> Vec<Violation> {
+        layout_violations(&self.items)
+    }
+
     pub fn can_exchange_double(&self, index: usize) -> bool {
         let num_empty: usize = self
             .items
@@ -109,20 +191,472 @@ impl Bag {
         // a two-slot item
         num_empty >= 2
             || (num_empty == 1 && !self.items[index].is_empty())
-            || self.items.get(index + 1).map_or(false, Item::is_slot_two)
+            || self.items.get(index + 1).is_some_and(Item::is_slot_two)
     }
 
     pub fn is_slot_two(&self, index: usize) -> bool {
-        self.items.get(index).map_or(false, Item::is_slot_two)
+        self.items.get(index).is_some_and(Item::is_slot_two)
+    }
+
+    pub fn equipped_item(&self) -> Option<EquippedItem> {
+        let index = usize::try_from(self.equipped_item_index).ok()?;
+        let item = self.items.get(index)?;
+        (!item.is_empty() && !item.is_slot_two()).then(|| EquippedItem {
+            index,
+            item: item.clone(),
+        })
+    }
+
+    /// Point the equipped index back at the item that was equipped before an exchange. If the
+    /// item is no longer in the bag, nothing is equipped anymore.
+    pub fn fix_equipped_item(&mut self, equipped: Option<&EquippedItem>) {
+        // if nothing was equipped, nothing can have moved out from under the index
+        let Some(equipped) = equipped else {
+            return;
+        };
+        // if the index itself changed, the player equipped or unequipped something, and the game
+        // already knows what it's doing
+        if self.equipped_item_index != equipped.index as i32 {
+            return;
+        }
+        if self.items[equipped.index].id == equipped.item.id {
+            return;
+        }
+
+        let new_index = self
+            .items
+            .iter()
+            .position(|i| i.id == equipped.item.id)
+            .map_or(-1, |i| i as i32);
+        log::debug!(
+            "Equipped item {} moved from {} to {}",
+            equipped.item,
+            equipped.index,
+            new_index
+        );
+        self.equipped_item_index = new_index;
+    }
+
+    pub fn personal_item(&self) -> Option<&Item> {
+        (!self.personal_item.is_empty()).then_some(&self.personal_item)
+    }
+
+    /// Undo an exchange that gave `item` away for `received` by putting `item` back where
+    /// `received` ended up. If nothing was received, `item` left a hole, which is the personal item
+    /// slot if that's empty. Fails if there's nowhere to put `item`.
+    pub fn undo_exchange(&mut self, item: Item, received: &Item) -> Result<(), Item> {
+        if !received.is_empty() {
+            if let Some(index) = self.items.iter().position(|i| i == received) {
+                // the second half of a two-slot item goes with it
+                if received.is_two_slot_item() && self.is_slot_two(index + 1) {
+                    self.items[index + 1] = Item::empty();
+                }
+                self.items[index] = item;
+                return Ok(());
+            }
+        }
+
+        if self.personal_item.is_empty() {
+            self.personal_item = item;
+            return Ok(());
+        }
+        match self.items.iter_mut().find(|i| i.is_empty()) {
+            Some(slot) => {
+                *slot = item;
+                Ok(())
+            }
+            None => Err(item),
+        }
+    }
+
+    /// Whether `undo_exchange` would be able to put `item` back
+    pub fn can_undo_exchange(&self, item: &Item, received: &Item) -> bool {
+        self.clone().undo_exchange(item.clone(), received).is_ok()
+    }
+
+    pub fn with_items(items: [Item; BAG_SIZE]) -> Self {
+        Self {
+            items,
+            ..Self::empty()
+        }
+    }
+
+    #[cfg(test)]
+    pub const fn items(&self) -> &[Item; BAG_SIZE] {
+        &self.items
+    }
+
+    #[cfg(test)]
+    pub fn items_mut(&mut self) -> &mut [Item; BAG_SIZE] {
+        &mut self.items
+    }
+}
+
+/// The result of updating the box from the view
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViewUpdate {
+    /// The game isn't done with the view yet, so the box is unchanged
+    NotReady,
+    Accepted,
+    /// The game tried to put a personal item in the box, so the box ignored the exchange. The
+    /// player has to get the item back in place of the box item it was exchanged for. This only
+    /// happens if the caller said the player can take the item back.
+    Refused {
+        personal_item: Item,
+        exchanged_for: Item,
+    },
+}
+
+/// A way in which the box or a bag breaks the layout rules the game expects
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// SLOT_TWO that doesn't follow a two-slot item
+    OrphanedSlotTwo { index: usize },
+    /// Two-slot item that isn't followed by SLOT_TWO
+    MissingSlotTwo { index: usize },
+    /// Two-slot item that starts in the right column
+    MisalignedDouble { index: usize },
+    /// Item that comes after an empty slot
+    Gap { index: usize },
+    /// View slot that doesn't match the part of the box it's showing
+    ViewMismatch { index: usize },
+    /// View that extends past the end of the box
+    IndexOutOfRange { index: usize, len: usize },
+    /// View that starts in the right column
+    OddIndex { index: usize },
+}
+
+impl Violation {
+    /// Whether this leaves a two-slot item split up, as opposed to just being out of order
+    pub const fn is_broken(&self) -> bool {
+        matches!(
+            self,
+            Self::OrphanedSlotTwo { .. } | Self::MissingSlotTwo { .. }
+        )
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OrphanedSlotTwo { index } => write!(f, "orphaned slot two at {}", index),
+            Self::MissingSlotTwo { index } => {
+                write!(f, "two-slot item at {} has no slot two", index)
+            }
+            Self::MisalignedDouble { index } => write!(f, "misaligned two-slot item at {}", index),
+            Self::Gap { index } => write!(f, "gap before {}", index),
+            Self::ViewMismatch { index } => {
+                write!(f, "view slot {} doesn't match the box", index)
+            }
+            Self::IndexOutOfRange { index, len } => {
+                write!(f, "view at {} doesn't fit in a box of {} slots", index, len)
+            }
+            Self::OddIndex { index } => write!(f, "view index {} is odd", index),
+        }
+    }
+}
+
+fn layout_violations(items: &[Item]) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|j| &items[j]);
+        if item.is_slot_two() {
+            if !previous.is_some_and(Item::is_two_slot_item) {
+                violations.push(Violation::OrphanedSlotTwo { index: i });
+            }
+        } else if item.is_two_slot_item() {
+            if i & 1 == 1 {
+                violations.push(Violation::MisalignedDouble { index: i });
+            }
+            if !items.get(i + 1).is_some_and(Item::is_slot_two) {
+                violations.push(Violation::MissingSlotTwo { index: i });
+            }
+        }
+
+        if !item.is_empty() && previous.is_some_and(Item::is_empty) {
+            violations.push(Violation::Gap { index: i });
+        }
+    }
+    violations
+}
+
+fn describe_violations(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(Violation::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl fmt::Display for ItemBox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Item box ({}, index {}, {} slots):",
+            if self.is_open { "open" } else { "closed" },
+            self.index,
+            self.items.len()
+        )?;
+
+        // print the box in rows of two like the inventory menu shows it. rows in the view are
+        // marked with |, and the row at the current index is marked with >.
+        let view_range = self.index..self.index + BAG_SIZE;
+        for (row, pair) in self.items.chunks(2).enumerate() {
+            let row_index = row * 2;
+            let marker = if row_index == self.index {
+                '>'
+            } else if view_range.contains(&row_index) {
+                '|'
+            } else {
+                ' '
+            };
+            match pair.get(1) {
+                Some(item) => {
+                    writeln!(f, "{} {:>3}  {:<36} | {}", marker, row_index, pair[0], item)?
+                }
+                None => writeln!(f, "{} {:>3}  {}", marker, row_index, pair[0])?,
+            }
+        }
+
+        // the view should always match the box, but if the game has changed it and we haven't
+        // picked up the changes yet, show what's in it
+        if self.items.get(view_range) != Some(&self.view.items[..]) {
+            writeln!(f, "  view: {}", self.view)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Bag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, item) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "[{}]", item)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Bag {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let items: [Item; BAG_SIZE] = parse_item_list(s)?
+            .try_into()
+            .map_err(|_| anyhow!("Bag does not have {} items", BAG_SIZE))?;
+        Ok(Self::with_items(items))
+    }
+}
+
+fn parse_item_list(s: &str) -> Result<Vec<Item>> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    s.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or_else(|| anyhow!("Invalid item list {:?}", s))?
+        .split("] [")
+        .map(str::parse)
+        .collect()
+}
+
+// parses the Display form of the box back into a box, so dumps from the log can be replayed
+impl FromStr for ItemBox {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut lines = s.lines().filter(|l| !l.trim().is_empty());
+        let header = lines.next().ok_or_else(|| anyhow!("Box text is empty"))?;
+        let (state, index) = header
+            .trim()
+            .strip_prefix("Item box (")
+            .and_then(|h| h.split_once(" slots):"))
+            .and_then(|(h, _)| h.rsplit_once(", "))
+            .and_then(|(h, _)| h.split_once(", index "))
+            .ok_or_else(|| anyhow!("Invalid box header {:?}", header))?;
+        let is_open = match state {
+            "open" => true,
+            "closed" => false,
+            _ => bail!("Invalid box state {:?}", state),
+        };
+        let index: usize = index.parse()?;
+
+        let mut items = Vec::new();
+        let mut view = None;
+        for line in lines {
+            let trimmed = line.trim();
+            if let Some(view_items) = trimmed.strip_prefix("view:") {
+                view = Some(parse_item_list(view_items)?);
+                continue;
+            }
+
+            // skip the row marker, then the row index is separated from the items by two spaces
+            let (row_index, row) = line
+                .get(2..)
+                .and_then(|l| l.trim_start().split_once("  "))
+                .ok_or_else(|| anyhow!("Invalid box row {:?}", line))?;
+            if row_index.parse::<usize>()? != items.len() {
+                bail!("Box row {:?} is out of order", line);
+            }
+            match row.split_once(" | ") {
+                Some((left, right)) => {
+                    items.push(left.parse()?);
+                    items.push(right.parse()?);
+                }
+                None => items.push(row.parse()?),
+            }
+        }
+
+        let view_items = match view {
+            Some(view_items) => view_items,
+            None => items
+                .get(index..index + BAG_SIZE)
+                .ok_or_else(|| anyhow!("Box is too small for a view at index {}", index))?
+                .to_vec(),
+        };
+        let view_items: [Item; BAG_SIZE] = view_items
+            .try_into()
+            .map_err(|_| anyhow!("View does not have {} items", BAG_SIZE))?;
+
+        Ok(Self {
+            is_open,
+            items,
+            index,
+            view: Bag::with_items(view_items),
+            personal_items: Vec::new(),
+        })
+    }
+}
+
+/// Split a list in Debug form on the commas that aren't nested inside another value
+fn split_debug_list(s: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    let last = s[start..].trim();
+    if !last.is_empty() {
+        parts.push(last);
+    }
+    parts
+}
+
+/// The fields of a struct in Debug form, like `Item { id: 6, count: 1 }`
+fn debug_fields<'a>(s: &'a str, name: &str) -> Result<Vec<(&'a str, &'a str)>> {
+    s.trim()
+        .strip_prefix(name)
+        .and_then(|s| s.trim_start().strip_prefix('{'))
+        .and_then(|s| s.strip_suffix('}'))
+        .ok_or_else(|| anyhow!("Invalid {} {:?}", name, s))
+        .and_then(|body| {
+            split_debug_list(body)
+                .into_iter()
+                .map(|field| {
+                    field
+                        .split_once(": ")
+                        .ok_or_else(|| anyhow!("Invalid {} field {:?}", name, field))
+                })
+                .collect()
+        })
+}
+
+fn debug_field<'a>(fields: &[(&str, &'a str)], name: &str) -> Result<&'a str> {
+    fields
+        .iter()
+        .find(|(field, _)| *field == name)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| anyhow!("Missing field {}", name))
+}
+
+fn debug_items(s: &str) -> Result<Vec<Item>> {
+    let list = s
+        .trim()
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .ok_or_else(|| anyhow!("Invalid item list {:?}", s))?;
+    split_debug_list(list)
+        .into_iter()
+        .map(|item| {
+            let fields = debug_fields(item, "Item")?;
+            Ok(Item::new(
+                debug_field(&fields, "id")?.parse()?,
+                debug_field(&fields, "count")?.parse()?,
+            ))
+        })
+        .collect()
+}
+
+impl ItemBox {
+    /// Parse the Debug form of the box, which is how older versions of the mod logged it
+    pub fn from_debug(s: &str) -> Result<Self> {
+        let fields = debug_fields(s, "ItemBox")?;
+        let view_fields = debug_fields(debug_field(&fields, "view")?, "Bag")?;
+        let view_items: [Item; BAG_SIZE] = debug_items(debug_field(&view_fields, "items")?)?
+            .try_into()
+            .map_err(|_| anyhow!("View does not have {} items", BAG_SIZE))?;
+        Ok(Self {
+            is_open: debug_field(&fields, "is_open")?.parse()?,
+            items: debug_items(debug_field(&fields, "items")?)?,
+            index: debug_field(&fields, "index")?.parse()?,
+            view: Bag::with_items(view_items),
+            personal_items: Vec::new(),
+        })
     }
 }
 
+/// How an item is referred to in the text form of the box: by name if it's in the catalog, or by
+/// ID otherwise
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ItemRef {
+    Id(i32),
+    Name(String),
+}
+
+const fn default_count() -> i32 {
+    1
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ItemEntry {
+    item: ItemRef,
+    #[serde(default = "default_count")]
+    count: i32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BoxText {
+    items: Vec<ItemEntry>,
+}
+
 #[derive(Debug)]
 pub struct ItemBox {
     is_open: bool,
     items: Vec<Item>,
     index: usize,
     view: Bag,
+    personal_items: Vec<i32>,
+}
+
+impl Default for ItemBox {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ItemBox {
@@ -132,6 +666,7 @@ impl ItemBox {
             items: Vec::new(),
             index: 0,
             view: Bag::empty(),
+            personal_items: Vec::new(),
         }
     }
 
@@ -222,30 +757,146 @@ impl ItemBox {
         }
         self.view
             .items
-            .clone_from_slice(&self.items[self.index..self.index + BAG_SIZE])
+            .clone_from_slice(&self.items[self.index..self.index + BAG_SIZE]);
+        // nothing in the box can be equipped, and any index the game left here would point at
+        // whatever just scrolled into that slot
+        self.view.equipped_item_index = -1;
+        // the view stands in for the partner's bag, but the box itself has no personal item
+        self.view.personal_item = Item::empty();
+    }
+
+    /// Remember an item as a character's personal item so it never gets put in the box
+    pub fn add_personal_item(&mut self, id: i32) {
+        if id != 0 && !self.personal_items.contains(&id) {
+            log::debug!("Item {} is a personal item", id);
+            self.personal_items.push(id);
+        }
+    }
+
+    /// Take any personal items we know about out of the box. Box data saved before we learned
+    /// them, or items carried over or added at the start of a new game, can include them.
+    pub fn remove_personal_items(&mut self) {
+        let mut removed = false;
+        for item in &mut self.items {
+            if !item.is_empty() && self.personal_items.contains(&item.id) {
+                log::warn!("Removing personal item {} from the box", item);
+                *item = Item::empty();
+                removed = true;
+            }
+        }
+        if removed {
+            self.organize();
+            self.update_view();
+        }
+    }
+
+    /// Find a personal item the game put in the view, along with the box item it replaced. Only
+    /// slots the game changed count, since the box may already hold a personal item from before
+    /// we knew about it.
+    fn find_personal_item(&self) -> Option<(Item, Item)> {
+        if !self.view.personal_item.is_empty() {
+            // the box has no personal item, so nothing came out of this slot
+            return Some((self.view.personal_item.clone(), Item::empty()));
+        }
+        let backing = &self.items[self.index..self.index + BAG_SIZE];
+        self.view
+            .items
+            .iter()
+            .zip(backing)
+            .find(|(item, old_item)| {
+                item != old_item && !item.is_empty() && self.personal_items.contains(&item.id)
+            })
+            .map(|(item, old_item)| (item.clone(), old_item.clone()))
+    }
+
+    /// Everything wrong with the box and its view. This should be empty whenever the box isn't in
+    /// the middle of an exchange; making room for a two-slot item leaves a gap until the game
+    /// fills it.
+    pub fn check_invariants(&self) -> Vec<Violation> {
+        let mut violations = layout_violations(&self.items);
+        if self.index & 1 != 0 {
+            violations.push(Violation::OddIndex { index: self.index });
+        }
+        match self.items.get(self.index..self.index + BAG_SIZE) {
+            Some(backing) => violations.extend(
+                backing
+                    .iter()
+                    .zip(&self.view.items)
+                    .enumerate()
+                    .filter(|(_, (item, view_item))| item != view_item)
+                    .map(|(index, _)| Violation::ViewMismatch { index }),
+            ),
+            None => violations.push(Violation::IndexOutOfRange {
+                index: self.index,
+                len: self.items.len(),
+            }),
+        }
+        violations
     }
 
-    pub fn update_from_view(&mut self) {
+    /// Copy the changes the game made to the view into the box, unless the game tried to put a
+    /// personal item in the box, in which case the view goes back to how it was. That's only done
+    /// if `can_give_back` says the player can take the personal item back in exchange for the box
+    /// item they got for it; otherwise, the box keeps the personal item so it isn't lost.
+    pub fn update_from_view(
+        &mut self,
+        can_give_back: impl FnOnce(&Item, &Item) -> bool,
+    ) -> ViewUpdate {
         // we want to wait until the game has finished organizing the view before we update
         if !self.view.is_valid() {
             log::debug!("Skipping update_from_view because the view has not yet been organized");
-            return;
+            return ViewUpdate::NotReady;
+        }
+
+        if let Some((personal_item, exchanged_for)) = self.find_personal_item() {
+            if can_give_back(&personal_item, &exchanged_for) {
+                log::warn!(
+                    "Refusing to put personal item {} in the box in exchange for {}",
+                    personal_item,
+                    exchanged_for
+                );
+                self.update_view();
+                return ViewUpdate::Refused {
+                    personal_item,
+                    exchanged_for,
+                };
+            }
+            log::warn!(
+                "{} {} in the box because the player can't take it back",
+                KEEPING_PERSONAL_ITEM,
+                personal_item
+            );
         }
 
+        let before = self.snapshot();
         let view_end = self.index + BAG_SIZE;
         let view_slice = &mut self.items[self.index..view_end];
         view_slice.clone_from_slice(&self.view.items);
+        // the box has no personal slot, so anything the game put there goes in with the rest
+        if !self.view.personal_item.is_empty() {
+            self.items.push(self.view.personal_item.clone());
+        }
         // re-organize the box to account for any gaps or oddities in the view
         self.organize();
         self.update_view();
         if !self.view.is_valid() {
-            log::warn!("View is in an invalid state after updating: {:?}", self);
+            log::warn!(
+                "View is in an invalid state after updating ({}): {}\n{}",
+                before.diff(&self.snapshot()),
+                describe_violations(&self.view.check_invariants()),
+                self
+            );
+        } else if log::log_enabled!(log::Level::Trace) {
+            log::trace!("Box updated from view: {}", before.diff(&self.snapshot()));
         }
+
+        ViewUpdate::Accepted
     }
 
     pub fn make_room_for_double(&mut self, index: usize) {
         // we only need to do something if we don't already have room
         if !self.view.can_exchange_double(index) {
+            let before = self.snapshot();
             let box_index = index + self.index;
             self.items.insert(box_index + 1, Item::empty());
             if index == BAG_SIZE - 1 {
@@ -257,22 +908,101 @@ impl ItemBox {
             self.fix_misaligned(box_index + 1);
             self.update_view();
             if self.view.is_broken() {
+                // the gap we just made is expected, so only complain about split-up items
+                let mut violations = self.view.check_invariants();
+                violations.retain(Violation::is_broken);
                 log::warn!(
-                    "View is in a broken state after making room for two-slot item at index {}: {:?}",
+                    "View is in a broken state after making room for two-slot item at index {} ({}): {}\n{}",
                     index,
+                    before.diff(&self.snapshot()),
+                    describe_violations(&violations),
                     self
                 );
             }
         }
     }
 
+    pub fn snapshot(&self) -> BoxSnapshot {
+        // trailing empty slots are just padding for the view, so there's no need to keep them
+        let num_items = self
+            .items
+            .iter()
+            .rposition(|i| !i.is_empty())
+            .map_or(0, |i| i + 1);
+        BoxSnapshot {
+            items: self.items[..num_items].to_vec(),
+            index: self.index,
+        }
+    }
+
+    /// Put the box back the way it was when the snapshot was taken
+    pub fn restore(&mut self, snapshot: &BoxSnapshot) {
+        log::debug!("Restoring box from snapshot");
+        self.items.clone_from(&snapshot.items);
+        self.index = snapshot.index;
+        self.update_view();
+    }
+
+    /// Write the box contents as JSON that can be shared, edited, and read back with `from_json`
+    pub fn to_json(&self) -> Result<String> {
+        // empty slots and SLOT_TWO are implied by the layout rules, so we leave them out
+        let items = self
+            .items
+            .iter()
+            .filter(|i| !i.is_empty() && !i.is_slot_two())
+            .map(|i| ItemEntry {
+                item: catalog::item_name(i.id)
+                    .map_or(ItemRef::Id(i.id), |name| ItemRef::Name(String::from(name))),
+                count: i.count,
+            })
+            .collect();
+
+        Ok(serde_json::to_string_pretty(&BoxText { items })?)
+    }
+
+    /// Read box contents from JSON, fixing up the layout the same way the game would
+    pub fn from_json(text: &str) -> Result<Self> {
+        let box_text: BoxText = serde_json::from_str(text)?;
+        let mut items = Vec::with_capacity(box_text.items.len());
+        for entry in box_text.items {
+            let id = match entry.item {
+                ItemRef::Id(id) => id,
+                ItemRef::Name(name) => catalog::item_id(&name)
+                    .ok_or_else(|| anyhow!("Unknown item name {:?}", name))?,
+            };
+            let item = Item {
+                id,
+                count: entry.count,
+            };
+            if item.is_empty() || item.is_slot_two() {
+                continue;
+            }
+
+            let is_two_slot_item = item.is_two_slot_item();
+            items.push(item);
+            if is_two_slot_item {
+                items.push(Item::slot_two());
+            }
+        }
+
+        let mut item_box = Self::new();
+        item_box.set_contents(items);
+        item_box.organize();
+        item_box.update_view();
+        Ok(item_box)
+    }
+
     pub fn open(&mut self) {
         if !self.is_open {
             self.is_open = true;
             self.index = 0;
             self.update_view();
             if !self.view.is_valid() {
-                log::warn!("View is in an invalid state after opening: {:?}", self);
+                log::warn!(
+                    "View is in an invalid state after opening: {}\n{}",
+                    describe_violations(&self.view.check_invariants()),
+                    self
+                );
             }
         }
     }
@@ -283,7 +1013,7 @@ impl ItemBox {
 
     pub fn scroll_view(&mut self, offset: isize) -> bool {
         // index must be a multiple of 2; round offset up if it was odd
-        let mut new_index = self.index as isize + (offset + 1) & !1;
+        let mut new_index = (self.index as isize + offset + 1) & !1;
         if new_index < 0 {
             new_index = 0;
         } else {
@@ -300,7 +1030,11 @@ impl ItemBox {
             self.index = new_index;
             self.update_view();
             if !self.view.is_valid() {
-                log::warn!("View is in an invalid state after scrolling: {:?}", self);
+                log::warn!(
+                    "View is in an invalid state after scrolling: {}\n{}",
+                    describe_violations(&self.view.check_invariants()),
+                    self
+                );
             }
             true
         } else {
@@ -330,39 +1064,224 @@ impl ItemBox {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// A copy of the box contents and scroll position at a point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoxSnapshot {
+    items: Vec<Item>,
+    index: usize,
+}
 
-    #[test]
-    fn organize_missing_second_half() {
-        let mut item_box = ItemBox::new();
-        item_box.set_contents(vec![
-            Item { id: 6, count: 1 }, // shotgun, two-slot item
-            Item { id: 55, count: 7 },
-            Item { id: 32, count: 15 },
-        ]);
-        item_box.organize();
-        let new_contents = item_box.get_contents();
-        let pos = new_contents.iter().position(|i| i.id == 6).unwrap();
-        assert_eq!(pos & 1, 0); // must be in an even-numbered (left-hand) slot
-        assert!(new_contents[pos + 1].is_slot_two()); // must be followed by SLOT_TWO
-        assert_eq!(new_contents.iter().filter(|i| !i.is_empty()).count(), 4); // must have 4 items, counting the newly inserted SLOT_TWO as an item
-    }
+impl BoxSnapshot {
+    pub fn diff(&self, other: &Self) -> BoxDiff {
+        // SLOT_TWO entries always move with their two-slot item, so we leave them out of the diff
+        let entries = |items: &[Item]| -> Vec<(usize, Item)> {
+            items
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter(|(_, i)| !i.is_empty() && !i.is_slot_two())
+                .collect()
+        };
+        let mut old_entries = entries(&self.items);
+        let mut new_entries = entries(&other.items);
+        let mut changes = Vec::new();
 
-    #[test]
-    fn organize_missing_first_half() {
-        let mut item_box = ItemBox::new();
-        item_box.set_contents(vec![
-            Item { id: 55, count: 7 },
-            Item {
-                id: SLOT_TWO,
-                count: 1,
-            }, // SLOT_TWO but the preceding item is not a two-slot item
-            Item { id: 32, count: 15 },
-            Item { id: 3, count: 5 },
-        ]);
-        item_box.organize();
+        // anything with the same ID in the same position hasn't moved, although its count may
+        // have changed
+        old_entries.retain(|(old_index, old_item)| {
+            let Some(pos) = new_entries.iter().position(|(new_index, new_item)| {
+                new_index == old_index && new_item.id == old_item.id
+            }) else {
+                return true;
+            };
+
+            let (index, new_item) = new_entries.remove(pos);
+            if new_item.count != old_item.count {
+                changes.push(BoxChange::CountChanged {
+                    index,
+                    id: new_item.id,
+                    old_count: old_item.count,
+                    new_count: new_item.count,
+                });
+            }
+            false
+        });
+
+        // of what's left, pair up items with the same ID in order as moves
+        for (from, old_item) in old_entries {
+            match new_entries
+                .iter()
+                .position(|(_, new_item)| new_item.id == old_item.id)
+            {
+                Some(pos) => {
+                    let (to, new_item) = new_entries.remove(pos);
+                    if new_item.count != old_item.count {
+                        changes.push(BoxChange::CountChanged {
+                            index: to,
+                            id: new_item.id,
+                            old_count: old_item.count,
+                            new_count: new_item.count,
+                        });
+                    }
+                    changes.push(BoxChange::Moved {
+                        from,
+                        to,
+                        item: new_item,
+                    });
+                }
+                None => changes.push(BoxChange::Removed {
+                    index: from,
+                    item: old_item,
+                }),
+            }
+        }
+
+        changes.extend(
+            new_entries
+                .into_iter()
+                .map(|(index, item)| BoxChange::Added { index, item }),
+        );
+
+        BoxDiff {
+            changes,
+            old_index: self.index,
+            new_index: other.index,
+        }
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoxChange {
+    Added {
+        index: usize,
+        item: Item,
+    },
+    Removed {
+        index: usize,
+        item: Item,
+    },
+    Moved {
+        from: usize,
+        to: usize,
+        item: Item,
+    },
+    CountChanged {
+        index: usize,
+        id: i32,
+        old_count: i32,
+        new_count: i32,
+    },
+}
+
+impl fmt::Display for BoxChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added { index, item } => write!(f, "added {} at {}", item, index),
+            Self::Removed { index, item } => write!(f, "removed {} from {}", item, index),
+            Self::Moved { from, to, item } => write!(f, "moved {} from {} to {}", item, from, to),
+            Self::CountChanged {
+                index,
+                id,
+                old_count,
+                new_count,
+            } => write!(
+                f,
+                "count of {} at {} changed from {} to {}",
+                id, index, old_count, new_count
+            ),
+        }
+    }
+}
+
+/// The structural differences between two box snapshots
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoxDiff {
+    changes: Vec<BoxChange>,
+    old_index: usize,
+    new_index: usize,
+}
+
+impl BoxDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.old_index == self.new_index
+    }
+
+    pub fn changes(&self) -> &[BoxChange] {
+        &self.changes
+    }
+}
+
+impl fmt::Display for BoxDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+
+        let mut needs_separator = false;
+        for change in &self.changes {
+            if needs_separator {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", change)?;
+            needs_separator = true;
+        }
+
+        if self.old_index != self.new_index {
+            if needs_separator {
+                write!(f, "; ")?;
+            }
+            write!(
+                f,
+                "view moved from {} to {}",
+                self.old_index, self.new_index
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn organize_missing_second_half() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 6, count: 1 }, // shotgun, two-slot item
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+        ]);
+        item_box.organize();
+        let new_contents = item_box.get_contents();
+        let pos = new_contents.iter().position(|i| i.id == 6).unwrap();
+        assert_eq!(pos & 1, 0); // must be in an even-numbered (left-hand) slot
+        assert!(new_contents[pos + 1].is_slot_two()); // must be followed by SLOT_TWO
+        assert_eq!(new_contents.iter().filter(|i| !i.is_empty()).count(), 4); // must have 4 items, counting the newly inserted SLOT_TWO as an item
+    }
+
+    #[test]
+    fn organize_missing_first_half() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 55, count: 7 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            }, // SLOT_TWO but the preceding item is not a two-slot item
+            Item { id: 32, count: 15 },
+            Item { id: 3, count: 5 },
+        ]);
+        item_box.organize();
         let new_contents = item_box.get_contents();
         assert!(!new_contents.iter().any(Item::is_slot_two));
         assert_eq!(new_contents.iter().filter(|i| !i.is_empty()).count(), 3);
@@ -471,7 +1390,8 @@ mod tests {
                 ],
                 personal_item: Item { id: 0, count: 0 },
                 equipped_item_index: -1
-            }
+            },
+            personal_items: vec![],
         };
         assert!(item_box.view.is_valid());
 
@@ -538,13 +1458,13 @@ mod tests {
         item_box.open();
         assert!(item_box.view().is_valid());
         item_box.view().items[4] = Item { id: 4, count: 9 };
-        item_box.update_from_view();
+        assert_eq!(item_box.update_from_view(|_, _| true), ViewUpdate::Accepted);
         let contents = item_box.get_contents();
         assert!(!contents.iter().any(|i| i.id == 3));
         assert!(contents.iter().any(|i| i.id == 4));
 
         item_box.view().items[BAG_SIZE - 1] = Item::empty();
-        item_box.update_from_view();
+        item_box.update_from_view(|_, _| true);
         // 7 items - the 1 we removed == 6
         assert_eq!(
             item_box
@@ -559,6 +1479,560 @@ mod tests {
         assert!(view.items.iter().all(|i| !i.is_empty()));
         // the last item in the view should be the item that was shifted up from the end, 14
         assert_eq!(view.items[BAG_SIZE - 1].id, 14);
+
+        // the game isn't done with the view yet, so the box shouldn't change
+        let before = item_box.snapshot();
+        item_box.view().items[0] = Item::slot_two();
+        assert_eq!(item_box.update_from_view(|_, _| true), ViewUpdate::NotReady);
+        assert_eq!(item_box.snapshot(), before);
+    }
+
+    #[test]
+    fn snapshot_diff() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 6, count: 1 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+        ]);
+        let before = item_box.snapshot();
+        assert!(before.diff(&item_box.snapshot()).is_empty());
+        assert_eq!(before.diff(&before).to_string(), "no changes");
+
+        item_box.set_contents(vec![
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 10 },
+            Item { id: 6, count: 1 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 4, count: 9 },
+        ]);
+        let diff = before.diff(&item_box.snapshot());
+        let changes = diff.changes();
+        assert_eq!(changes.len(), 6);
+        assert!(changes.contains(&BoxChange::Moved {
+            from: 0,
+            to: 2,
+            item: Item { id: 6, count: 1 },
+        }));
+        assert!(changes.contains(&BoxChange::Moved {
+            from: 2,
+            to: 0,
+            item: Item { id: 55, count: 7 },
+        }));
+        assert!(changes.contains(&BoxChange::CountChanged {
+            index: 1,
+            id: 32,
+            old_count: 15,
+            new_count: 10,
+        }));
+        assert!(changes.contains(&BoxChange::Removed {
+            index: 4,
+            item: Item { id: 14, count: 3 },
+        }));
+        assert!(changes.contains(&BoxChange::Added {
+            index: 4,
+            item: Item { id: 4, count: 9 },
+        }));
+        assert!(diff.to_string().contains("removed 14 x3 from 4"));
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+            Item { id: 4, count: 7 },
+            Item { id: 3, count: 5 },
+        ]);
+        item_box.open();
+        item_box.scroll_view(2);
+        let snapshot = item_box.snapshot();
+        // trailing empty slots should not be part of the snapshot
+        assert_eq!(snapshot.items().len(), 5);
+        assert_eq!(snapshot.index(), 2);
+
+        item_box.scroll_view(-2);
+        item_box.view().items[4] = Item::empty();
+        item_box.update_from_view(|_, _| true);
+        assert!(!item_box.get_contents().iter().any(|i| i.id == 3));
+
+        item_box.restore(&snapshot);
+        assert_eq!(item_box.snapshot(), snapshot);
+        assert_eq!(item_box.view().items[0].id, 14);
+        assert!(item_box.view().is_valid());
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 6, count: 1 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+        ]);
+        let text = item_box.to_json().unwrap();
+        // known items are written by name and unknown ones by ID
+        assert!(text.contains("\"Shotgun\""));
+        assert!(text.contains("55"));
+        assert!(!text.contains("180"));
+
+        let loaded = ItemBox::from_json(&text).unwrap();
+        assert_eq!(loaded.snapshot(), item_box.snapshot());
+    }
+
+    #[test]
+    fn json_by_name() {
+        let item_box = ItemBox::from_json(
+            r#"{
+                "items": [
+                    { "item": 55, "count": 7 },
+                    { "item": "hookshot" },
+                    { "item": "Shotgun", "count": 1 }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let contents = item_box.get_contents();
+        // the two-slot items would be misaligned after the first item, so they get moved forward
+        // and the first item goes after them
+        assert_eq!(contents[0], Item { id: 104, count: 1 });
+        assert!(contents[1].is_slot_two());
+        assert_eq!(contents[2], Item { id: 6, count: 1 });
+        assert!(contents[3].is_slot_two());
+        assert_eq!(contents[4], Item { id: 55, count: 7 });
+
+        assert!(ItemBox::from_json(r#"{ "items": [{ "item": "not an item" }] }"#).is_err());
+    }
+
+    #[test]
+    fn display() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 6, count: 1 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+            Item { id: 4, count: 7 },
+            Item { id: 3, count: 5 },
+        ]);
+        item_box.open();
+        item_box.scroll_view(2);
+        let text = item_box.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], "Item box (open, index 2, 8 slots):");
+        assert!(lines[1].starts_with("    0  Shotgun x1 "));
+        assert!(lines[1].ends_with(" | <slot two>"));
+        assert!(lines[2].starts_with(">   2  55 x7 "));
+        assert!(lines[2].ends_with(" | 32 x15"));
+        assert!(lines[3].starts_with("|   4  14 x3 "));
+        assert!(lines[4].starts_with("|   6  3 x5 "));
+        assert!(lines[4].ends_with(" | <empty>"));
+        assert_eq!(lines.len(), 5);
+
+        // a view that doesn't match the box should be shown separately
+        item_box.view().items[0] = Item::empty();
+        assert!(item_box.to_string().contains("view: [<empty>] [32 x15]"));
+    }
+
+    #[test]
+    fn parse_debug() {
+        // the way older versions logged the box
+        let text = "ItemBox { is_open: true, items: [Item { id: 6, count: 1 }, Item { id: 180, count: 1 }, \
+            Item { id: 55, count: 7 }], index: 0, view: Bag { unknown00: 0, items: [Item { id: 6, count: 1 }, \
+            Item { id: 180, count: 1 }, Item { id: 55, count: 7 }, Item { id: 0, count: 0 }, \
+            Item { id: 0, count: 0 }, Item { id: 0, count: 0 }], personal_item: Item { id: 0, count: 0 }, \
+            equipped_item_index: -1 } }";
+        let item_box = ItemBox::from_debug(text).unwrap();
+        assert!(item_box.is_open());
+        assert_eq!(
+            item_box.get_contents(),
+            [Item::new(6, 1), Item::slot_two(), Item::new(55, 7)]
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+        assert_eq!(item_box.view.items[2], Item::new(55, 7));
+
+        // the current Debug form has more fields, which are ignored
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![Item::new(31, 2)]);
+        let parsed = ItemBox::from_debug(&format!("{:?}", item_box)).unwrap();
+        assert_eq!(parsed.snapshot(), item_box.snapshot());
+
+        assert!(ItemBox::from_debug("ItemBox { is_open: true }").is_err());
+    }
+
+    #[test]
+    fn parse_display() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 7, count: 12 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+        ]);
+        item_box.open();
+        item_box.scroll_view(2);
+        let parsed: ItemBox = item_box.to_string().parse().unwrap();
+        assert!(parsed.is_open());
+        assert_eq!(parsed.snapshot(), item_box.snapshot());
+        assert_eq!(parsed.view.items, item_box.view.items);
+
+        // the view is only printed when it differs from the box, so make sure we pick it up
+        item_box.view().items[1] = Item::empty();
+        item_box.close();
+        let parsed: ItemBox = item_box.to_string().parse().unwrap();
+        assert!(!parsed.is_open());
+        assert!(parsed.view.items[1].is_empty());
+        assert_eq!(parsed.to_string(), item_box.to_string());
+
+        assert_eq!(
+            "Shotgun x1".parse::<Item>().unwrap(),
+            Item { id: 6, count: 1 }
+        );
+        assert_eq!("6 x1".parse::<Item>().unwrap(), Item { id: 6, count: 1 });
+        assert!("Shotgun".parse::<Item>().is_err());
+        assert!("Rubber Chicken x1".parse::<Item>().is_err());
+        assert!("Item box (ajar, index 0, 6 slots):"
+            .parse::<ItemBox>()
+            .is_err());
+    }
+
+    #[test]
+    fn invariants() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 6, count: 1 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+        ]);
+        item_box.open();
+        assert_eq!(item_box.check_invariants(), vec![]);
+        assert!(item_box.view.check_invariants().is_empty());
+
+        item_box.items = vec![
+            Item { id: 55, count: 7 },
+            Item { id: 6, count: 1 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item::empty(),
+            Item { id: 32, count: 15 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 104, count: 1 },
+        ];
+        item_box.index = 3;
+        assert_eq!(
+            item_box.check_invariants(),
+            vec![
+                Violation::MisalignedDouble { index: 1 },
+                Violation::Gap { index: 4 },
+                Violation::OrphanedSlotTwo { index: 5 },
+                Violation::MissingSlotTwo { index: 6 },
+                Violation::OddIndex { index: 3 },
+                Violation::IndexOutOfRange { index: 3, len: 7 },
+            ]
+        );
+
+        item_box.index = 0;
+        item_box.items.resize_with(BAG_SIZE + 1, Item::empty);
+        let violations = item_box.check_invariants();
+        assert!(violations.contains(&Violation::ViewMismatch { index: 0 }));
+        item_box.update_view();
+        assert!(!item_box
+            .check_invariants()
+            .iter()
+            .any(|v| matches!(v, Violation::ViewMismatch { .. })));
+        assert_eq!(
+            describe_violations(&violations[..2]),
+            "misaligned two-slot item at 1; gap before 4"
+        );
+
+        // the bag check agrees with is_valid
+        let bag = Bag::with_items(item_box.items[..BAG_SIZE].to_vec().try_into().unwrap());
+        assert!(!bag.is_valid());
+        assert_eq!(bag.check_invariants().len(), 3);
+    }
+
+    #[test]
+    fn equipped_item() {
+        let mut bag = Bag::with_items([
+            Item { id: 6, count: 7 },
+            Item {
+                id: SLOT_TWO,
+                count: 1,
+            },
+            Item { id: 3, count: 15 },
+            Item { id: 55, count: 7 },
+            Item::empty(),
+            Item::empty(),
+        ]);
+        // nothing equipped
+        assert_eq!(bag.equipped_item(), None);
+        bag.fix_equipped_item(None);
+        assert_eq!(bag.equipped_item_index, -1);
+
+        // items shifting around the equipped item
+        bag.equipped_item_index = 2;
+        let equipped = bag.equipped_item();
+        assert_eq!(
+            equipped,
+            Some(EquippedItem {
+                index: 2,
+                item: Item { id: 3, count: 15 },
+            })
+        );
+        bag.items.swap(2, 3);
+        bag.fix_equipped_item(equipped.as_ref());
+        assert_eq!(bag.equipped_item_index, 3);
+
+        // nothing moved
+        let equipped = bag.equipped_item();
+        bag.fix_equipped_item(equipped.as_ref());
+        assert_eq!(bag.equipped_item_index, 3);
+
+        // depositing the equipped item clears the index instead of equipping whatever slid into
+        // its slot
+        let equipped = bag.equipped_item();
+        bag.items[3] = Item { id: 32, count: 15 };
+        bag.fix_equipped_item(equipped.as_ref());
+        assert_eq!(bag.equipped_item_index, -1);
+        assert_eq!(bag.equipped_item(), None);
+
+        // if the game changed the index itself, we leave it alone
+        bag.equipped_item_index = 0;
+        let equipped = bag.equipped_item();
+        bag.items.swap(0, 2);
+        bag.equipped_item_index = 3;
+        bag.fix_equipped_item(equipped.as_ref());
+        assert_eq!(bag.equipped_item_index, 3);
+
+        // an index pointing at an empty slot or the second half of an item isn't an equipped item
+        bag.equipped_item_index = 4;
+        assert_eq!(bag.equipped_item(), None);
+        bag.equipped_item_index = 1;
+        assert_eq!(bag.equipped_item(), None);
+    }
+
+    #[test]
+    fn box_view_is_never_equipped() {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+            Item { id: 4, count: 7 },
+            Item { id: 3, count: 5 },
+        ]);
+        item_box.open();
+        item_box.view().equipped_item_index = 2;
+        item_box.scroll_view(2);
+        assert_eq!(item_box.view().equipped_item(), None);
+    }
+
+    fn personal_item_box() -> ItemBox {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 55, count: 7 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+        ]);
+        item_box.open();
+        item_box
+    }
+
+    #[test]
+    fn personal_item_slot_is_never_boxed() {
+        let mut item_box = personal_item_box();
+        assert_eq!(item_box.view().personal_item(), None);
+        let before = item_box.snapshot();
+
+        // the game tried to give the box a personal item
+        item_box.view().personal_item = Item { id: 120, count: 1 };
+        assert_eq!(
+            item_box.update_from_view(|_, _| true),
+            ViewUpdate::Refused {
+                personal_item: Item { id: 120, count: 1 },
+                exchanged_for: Item::empty(),
+            }
+        );
+        assert_eq!(item_box.snapshot(), before);
+        assert_eq!(item_box.view().personal_item(), None);
+    }
+
+    #[test]
+    fn known_personal_item_is_never_boxed() {
+        let mut item_box = personal_item_box();
+        item_box.add_personal_item(120);
+        let before = item_box.snapshot();
+
+        // exchanged into a regular slot
+        item_box.view().items[1] = Item { id: 120, count: 1 };
+        assert_eq!(
+            item_box.update_from_view(|_, _| true),
+            ViewUpdate::Refused {
+                personal_item: Item { id: 120, count: 1 },
+                exchanged_for: Item { id: 32, count: 15 },
+            }
+        );
+        // the box keeps the item the player tried to take for it
+        assert_eq!(item_box.snapshot(), before);
+        assert_eq!(item_box.view().items[1], Item { id: 32, count: 15 });
+    }
+
+    #[test]
+    fn boxed_personal_item_does_not_block_exchanges() {
+        // a personal item that got in the box before we knew about it
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(vec![
+            Item { id: 120, count: 1 },
+            Item { id: 32, count: 15 },
+            Item { id: 14, count: 3 },
+        ]);
+        item_box.open();
+        item_box.add_personal_item(120);
+
+        // the player swaps something in their bag for the 32, which has nothing to do with the 120
+        item_box.view().items[1] = Item { id: 55, count: 7 };
+        assert_eq!(item_box.update_from_view(|_, _| true), ViewUpdate::Accepted);
+        assert_eq!(
+            &item_box.get_contents()[..3],
+            [
+                Item { id: 120, count: 1 },
+                Item { id: 55, count: 7 },
+                Item { id: 14, count: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn personal_item_kept_if_it_cant_be_given_back() {
+        let mut item_box = personal_item_box();
+        item_box.add_personal_item(120);
+        item_box.view().items[1] = Item { id: 120, count: 1 };
+        assert_eq!(
+            item_box.update_from_view(|_, _| false),
+            ViewUpdate::Accepted
+        );
+        assert_eq!(item_box.get_contents()[1], Item { id: 120, count: 1 });
+
+        // the box has no personal slot, so that item goes in a regular one
+        let mut item_box = personal_item_box();
+        item_box.view().personal_item = Item { id: 121, count: 1 };
+        assert_eq!(
+            item_box.update_from_view(|_, _| false),
+            ViewUpdate::Accepted
+        );
+        assert_eq!(item_box.get_contents()[3], Item { id: 121, count: 1 });
+        assert_eq!(item_box.view().personal_item(), None);
+        assert_eq!(item_box.check_invariants(), vec![]);
+    }
+
+    #[test]
+    fn remove_personal_items() {
+        let mut item_box = ItemBox::new();
+        item_box.add_personal_item(120);
+        item_box.set_contents(vec![
+            Item { id: 55, count: 7 },
+            Item { id: 120, count: 1 },
+            Item { id: 6, count: 1 },
+            Item::slot_two(),
+        ]);
+        item_box.remove_personal_items();
+        // the shotgun moves back to the left column when the gap closes
+        assert_eq!(
+            &item_box.get_contents()[..4],
+            [
+                Item { id: 6, count: 1 },
+                Item::slot_two(),
+                Item { id: 55, count: 7 },
+                Item::empty(),
+            ]
+        );
+        assert_eq!(item_box.check_invariants(), vec![]);
+    }
+
+    #[test]
+    fn personal_item_not_shown_in_view() {
+        let mut item_box = personal_item_box();
+        item_box.view().personal_item = Item { id: 120, count: 1 };
+        // anything the game left in the personal slot is gone once the view is refreshed
+        item_box.scroll_view(2);
+        item_box.scroll_view(-2);
+        assert_eq!(item_box.view().personal_item(), None);
+    }
+
+    #[test]
+    fn undo_exchange() {
+        // the player's personal item went into the box's personal slot, and nothing came back
+        let mut bag = Bag::empty();
+        assert_eq!(
+            bag.undo_exchange(Item { id: 120, count: 1 }, &Item::empty()),
+            Ok(())
+        );
+        assert_eq!(bag.personal_item(), Some(&Item { id: 120, count: 1 }));
+
+        // the player got a two-slot item for it
+        let mut bag = Bag::with_items([
+            Item { id: 55, count: 7 },
+            Item { id: 6, count: 1 },
+            Item::slot_two(),
+            Item::empty(),
+            Item::empty(),
+            Item::empty(),
+        ]);
+        bag.personal_item = Item { id: 121, count: 1 };
+        assert_eq!(
+            bag.undo_exchange(Item { id: 120, count: 1 }, &Item { id: 6, count: 1 }),
+            Ok(())
+        );
+        assert_eq!(bag.items[1], Item { id: 120, count: 1 });
+        assert!(bag.items[2].is_empty());
+
+        // nothing came back and the personal slot is taken, so the item left a regular slot empty
+        assert_eq!(
+            bag.undo_exchange(Item { id: 122, count: 1 }, &Item::empty()),
+            Ok(())
+        );
+        assert_eq!(bag.items[2], Item { id: 122, count: 1 });
+
+        // a bag with no room can't have given anything away
+        let mut bag = Bag::with_items([const { Item::new(31, 1) }; BAG_SIZE]);
+        bag.personal_item = Item { id: 121, count: 1 };
+        assert_eq!(
+            bag.undo_exchange(Item { id: 120, count: 1 }, &Item::empty()),
+            Err(Item { id: 120, count: 1 })
+        );
+        assert!(!bag.can_undo_exchange(&Item { id: 120, count: 1 }, &Item::empty()));
+        assert!(bag.can_undo_exchange(&Item { id: 120, count: 1 }, &Item::new(31, 1)));
     }
 
     #[test]
@@ -0,0 +1,317 @@
+use super::inventory::{ItemBox, BAG_SIZE};
+
+const BAG_SIZE_I32: i32 = BAG_SIZE as i32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// The result of moving the cursor past the edge of the box view
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Navigation {
+    /// Index in the view the cursor should end up on
+    pub cursor: i32,
+    /// Whether the box view scrolled, in which case the game needs to redraw the bags
+    pub scrolled: bool,
+}
+
+impl Navigation {
+    const fn stay(cursor: i32) -> Self {
+        Self {
+            cursor,
+            scrolled: false,
+        }
+    }
+
+    const fn scroll(cursor: i32) -> Self {
+        Self {
+            cursor,
+            scrolled: true,
+        }
+    }
+}
+
+/// Decide where the cursor goes when the player tries to move it off the edge of the partner
+/// inventory, scrolling the box if it's open.
+///
+/// `cursor` is the index the game would move the cursor to for down, left, and right, or the
+/// current index for up, since the game doesn't calculate a new index when moving up from the
+/// top row.
+pub fn navigate(item_box: &mut ItemBox, cursor: i32, direction: Direction) -> Navigation {
+    match direction {
+        Direction::Up => {
+            if item_box.is_open() && item_box.scroll_view(-2) {
+                // if we've ended up on the second slot of a two-slot item, back up one
+                if item_box.view().is_slot_two(cursor as usize) {
+                    Navigation::scroll(cursor - 1)
+                } else {
+                    Navigation::scroll(cursor)
+                }
+            } else {
+                Navigation::stay(cursor)
+            }
+        }
+        Direction::Down => {
+            if !item_box.is_open() {
+                return Navigation::stay(cursor);
+            }
+
+            let mut navigation = Navigation::stay(cursor);
+            if cursor >= BAG_SIZE_I32 && item_box.scroll_view(2) {
+                navigation = Navigation::scroll(cursor - 2);
+            }
+            // if we've ended up on the second slot of a two-slot item, back up one
+            if navigation.cursor >= 0 && item_box.view().is_slot_two(navigation.cursor as usize) {
+                navigation.cursor -= 1;
+            }
+            navigation
+        }
+        Direction::Left => {
+            if item_box.is_open() && item_box.scroll_view(-2) {
+                // move to the end of the new top row, or the start if it's a two-slot item
+                Navigation::scroll(if item_box.view().is_slot_two(1) { 0 } else { 1 })
+            } else {
+                // we're already at the top, so wrap around to the last cell in the view
+                Navigation::stay(BAG_SIZE_I32 - 1)
+            }
+        }
+        Direction::Right => {
+            let is_past_end = cursor == BAG_SIZE_I32
+                || (cursor == BAG_SIZE_I32 - 1 && item_box.view().is_slot_two(cursor as usize));
+            if item_box.is_open() && is_past_end && item_box.scroll_view(2) {
+                // move to the start of the new bottom row
+                Navigation::scroll(BAG_SIZE_I32 - 2)
+            } else {
+                Navigation::stay(cursor % BAG_SIZE_I32)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHOTGUN: &str = r#"{ "item": "Shotgun" }"#;
+    const HOOKSHOT: &str = r#"{ "item": "Hookshot" }"#;
+
+    fn open_box(items: &[&str]) -> ItemBox {
+        let mut item_box =
+            ItemBox::from_json(&format!(r#"{{ "items": [{}] }}"#, items.join(","))).unwrap();
+        item_box.open();
+        item_box
+    }
+
+    fn numbered(count: i32) -> Vec<String> {
+        (1..=count)
+            .map(|i| format!(r#"{{ "item": {}, "count": 1 }}"#, i + 30))
+            .collect()
+    }
+
+    fn open_numbered_box(count: i32) -> ItemBox {
+        let items = numbered(count);
+        open_box(&items.iter().map(String::as_str).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn closed_box() {
+        let mut item_box = ItemBox::new();
+        assert_eq!(
+            navigate(&mut item_box, 0, Direction::Up),
+            Navigation::stay(0)
+        );
+        assert_eq!(
+            navigate(&mut item_box, 6, Direction::Down),
+            Navigation::stay(6)
+        );
+        assert_eq!(
+            navigate(&mut item_box, -1, Direction::Left),
+            Navigation::stay(5)
+        );
+        assert_eq!(
+            navigate(&mut item_box, 6, Direction::Right),
+            Navigation::stay(0)
+        );
+    }
+
+    #[test]
+    fn up_at_top() {
+        let mut item_box = open_numbered_box(10);
+        assert_eq!(
+            navigate(&mut item_box, 1, Direction::Up),
+            Navigation::stay(1)
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+    }
+
+    #[test]
+    fn up_scrolls() {
+        let mut item_box = open_numbered_box(10);
+        item_box.scroll_view(2);
+        assert_eq!(
+            navigate(&mut item_box, 1, Direction::Up),
+            Navigation::scroll(1)
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+    }
+
+    #[test]
+    fn up_onto_slot_two() {
+        let items = numbered(8);
+        let mut layout = vec![SHOTGUN];
+        layout.extend(items.iter().map(String::as_str));
+        let mut item_box = open_box(&layout);
+        item_box.scroll_view(2);
+        // scrolling up puts the shotgun in the top row, so the cursor has to move to its first half
+        assert_eq!(
+            navigate(&mut item_box, 1, Direction::Up),
+            Navigation::scroll(0)
+        );
+    }
+
+    #[test]
+    fn down_within_view() {
+        let mut item_box = open_numbered_box(10);
+        assert_eq!(
+            navigate(&mut item_box, 4, Direction::Down),
+            Navigation::stay(4)
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+    }
+
+    #[test]
+    fn down_scrolls() {
+        let mut item_box = open_numbered_box(10);
+        assert_eq!(
+            navigate(&mut item_box, 7, Direction::Down),
+            Navigation::scroll(5)
+        );
+        assert_eq!(item_box.snapshot().index(), 2);
+    }
+
+    #[test]
+    fn down_at_bottom() {
+        let mut item_box = open_numbered_box(4);
+        // the view can't scroll past the last row
+        item_box.scroll_view(2);
+        // there's nothing further down, so the cursor is left past the end of the view and the
+        // game will refuse the move
+        assert_eq!(
+            navigate(&mut item_box, 6, Direction::Down),
+            Navigation::stay(6)
+        );
+        assert_eq!(item_box.snapshot().index(), 2);
+    }
+
+    #[test]
+    fn down_onto_slot_two() {
+        let items = numbered(5);
+        let mut layout: Vec<_> = items.iter().map(String::as_str).take(4).collect();
+        layout.push(HOOKSHOT);
+        layout.push(&items[4]);
+        let mut item_box = open_box(&layout);
+        // the hookshot fills the bottom row of the view
+        assert!(item_box.view().is_slot_two(5));
+        assert_eq!(
+            navigate(&mut item_box, 5, Direction::Down),
+            Navigation::stay(4)
+        );
+    }
+
+    #[test]
+    fn down_scrolls_onto_slot_two() {
+        let items = numbered(6);
+        let mut layout: Vec<_> = items.iter().map(String::as_str).collect();
+        layout.push(HOOKSHOT);
+        let mut item_box = open_box(&layout);
+        // moving down from the right side of the bottom row lands on the hookshot's second half
+        assert_eq!(
+            navigate(&mut item_box, 7, Direction::Down),
+            Navigation::scroll(4)
+        );
+    }
+
+    #[test]
+    fn left_at_top() {
+        let mut item_box = open_numbered_box(10);
+        assert_eq!(
+            navigate(&mut item_box, -1, Direction::Left),
+            Navigation::stay(5)
+        );
+    }
+
+    #[test]
+    fn left_scrolls() {
+        let mut item_box = open_numbered_box(10);
+        item_box.scroll_view(2);
+        assert_eq!(
+            navigate(&mut item_box, -1, Direction::Left),
+            Navigation::scroll(1)
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+    }
+
+    #[test]
+    fn left_onto_slot_two() {
+        let items = numbered(8);
+        let mut layout = vec![SHOTGUN];
+        layout.extend(items.iter().map(String::as_str));
+        let mut item_box = open_box(&layout);
+        item_box.scroll_view(2);
+        assert_eq!(
+            navigate(&mut item_box, -1, Direction::Left),
+            Navigation::scroll(0)
+        );
+    }
+
+    #[test]
+    fn right_within_view() {
+        let mut item_box = open_numbered_box(10);
+        assert_eq!(
+            navigate(&mut item_box, 5, Direction::Right),
+            Navigation::stay(5)
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+    }
+
+    #[test]
+    fn right_scrolls() {
+        let mut item_box = open_numbered_box(10);
+        assert_eq!(
+            navigate(&mut item_box, 6, Direction::Right),
+            Navigation::scroll(4)
+        );
+        assert_eq!(item_box.snapshot().index(), 2);
+    }
+
+    #[test]
+    fn right_at_bottom() {
+        let mut item_box = open_numbered_box(2);
+        // nothing more to scroll to, so wrap around to the top
+        assert_eq!(
+            navigate(&mut item_box, 6, Direction::Right),
+            Navigation::stay(0)
+        );
+        assert_eq!(item_box.snapshot().index(), 0);
+    }
+
+    #[test]
+    fn right_from_two_slot_item() {
+        let items = numbered(6);
+        let mut layout: Vec<_> = items.iter().map(String::as_str).take(4).collect();
+        layout.push(HOOKSHOT);
+        layout.extend(items.iter().map(String::as_str).skip(4));
+        let mut item_box = open_box(&layout);
+        // moving right from the hookshot lands on its second half, which should scroll
+        assert!(item_box.view().is_slot_two(5));
+        assert_eq!(
+            navigate(&mut item_box, 5, Direction::Right),
+            Navigation::scroll(4)
+        );
+        assert_eq!(item_box.snapshot().index(), 2);
+    }
+}
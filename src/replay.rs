@@ -0,0 +1,673 @@
+//! Replay of the box operations recorded in a trace-level re0box.log
+//!
+//! At the trace level, the hooks log each operation that affects the box followed by a dump of the
+//! box afterwards. We rebuild the box from the first dump, replay each operation against it, and
+//! compare the result with the next dump to find the point where the mod's logic went wrong. Logs
+//! from older versions of the mod, which logged less, are replayed as far as they allow.
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use super::inventory::{Bag, ItemBox, KEEPING_PERSONAL_ITEM};
+use super::navigation::{navigate, Direction};
+
+const BOX_HEADER: &str = "Item box (";
+/// Older versions of the mod only logged the box in Debug form, when it was in an invalid state
+const DEBUG_BOX_HEADER: &str = "ItemBox {";
+
+/// An operation from the log that affects the box
+#[derive(Debug, Clone, PartialEq)]
+enum Operation {
+    NewGame,
+    LoadSlot(usize),
+    Open,
+    Close,
+    /// Older versions of the mod didn't log the cursor
+    ScrollUp(Option<i32>),
+    ScrollDown(i32),
+    ScrollLeft,
+    ScrollRight(i32),
+    /// Older versions of the mod didn't log the index, and the current version logs it in a
+    /// separate message
+    MakeRoomForDouble(Option<usize>),
+    /// The view as the game left it. Older versions of the mod didn't log it.
+    UpdateBox(Option<Bag>),
+    /// An update where the box kept a personal item because the player couldn't take it back.
+    /// The log only says so after the update, so this is picked out while replaying.
+    UpdateBoxKeepingPersonalItem(Bag),
+}
+
+impl Operation {
+    fn parse(message: &str) -> Option<Self> {
+        let (name, arg) = message
+            .trim()
+            .split_once(' ')
+            .unwrap_or((message.trim(), ""));
+        Some(match name {
+            "new_game" => Self::NewGame,
+            "load_slot" => Self::LoadSlot(arg.parse().ok()?),
+            "close_box" => Self::Close,
+            "scroll_up" if arg.is_empty() => Self::ScrollUp(None),
+            "scroll_up" => Self::ScrollUp(Some(arg.parse().ok()?)),
+            "scroll_down" => Self::ScrollDown(arg.parse().ok()?),
+            "scroll_left" => Self::ScrollLeft,
+            "scroll_right" => Self::ScrollRight(arg.parse().ok()?),
+            "update_box" if arg.is_empty() => Self::UpdateBox(None),
+            "update_box" => Self::UpdateBox(Some(arg.parse().ok()?)),
+            "make_room_for_double" if arg.is_empty() => Self::MakeRoomForDouble(None),
+            // open_box is logged even if the player didn't choose to open the box, so we look for
+            // the message that's only logged when it actually opens
+            "Opening" if arg == "item box" => Self::Open,
+            "Making" => Self::MakeRoomForDouble(Some(
+                arg.strip_prefix("room for two-slot item at ")?
+                    .parse()
+                    .ok()?,
+            )),
+            _ => return None,
+        })
+    }
+
+    /// Apply the operation to the box. Returns false if the outcome depends on information that
+    /// isn't in the log, in which case the box has to be picked up again from the next dump.
+    fn apply(&self, item_box: &mut ItemBox) -> bool {
+        match self {
+            // the new game box depends on the scope and on the carried-over and starting items in
+            // the config, and the loaded box comes from the save file
+            Self::NewGame | Self::LoadSlot(_) => return false,
+            Self::Open => item_box.open(),
+            Self::Close => {
+                item_box.close();
+                item_box.organize();
+            }
+            // the cursor only affects where the cursor ends up, not the box
+            Self::ScrollUp(cursor) => {
+                navigate(item_box, cursor.unwrap_or(0), Direction::Up);
+            }
+            Self::ScrollDown(cursor) => {
+                navigate(item_box, *cursor, Direction::Down);
+            }
+            Self::ScrollLeft => {
+                navigate(item_box, -1, Direction::Left);
+            }
+            Self::ScrollRight(cursor) => {
+                navigate(item_box, *cursor, Direction::Right);
+            }
+            Self::MakeRoomForDouble(Some(index)) => item_box.make_room_for_double(*index),
+            // the hook passes the call on to the game when the box is closed
+            Self::MakeRoomForDouble(None) if !item_box.is_open() => (),
+            // without the index, we can't tell whether or where the box made room
+            Self::MakeRoomForDouble(None) => return false,
+            // we don't know what was in the player's bag, but the log tells us whether the box kept
+            // a personal item
+            Self::UpdateBox(Some(view)) => {
+                if item_box.is_open() {
+                    *item_box.view() = view.clone();
+                    item_box.update_from_view(|_, _| true);
+                }
+            }
+            Self::UpdateBoxKeepingPersonalItem(view) => {
+                if item_box.is_open() {
+                    *item_box.view() = view.clone();
+                    item_box.update_from_view(|_, _| false);
+                }
+            }
+            // the game changes the view between hooks, so without the view, we can't know what
+            // changed
+            Self::UpdateBox(None) => return false,
+        }
+        true
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NewGame => write!(f, "new_game"),
+            Self::LoadSlot(index) => write!(f, "load_slot {}", index),
+            Self::Open => write!(f, "open_box"),
+            Self::Close => write!(f, "close_box"),
+            Self::ScrollUp(Some(cursor)) => write!(f, "scroll_up {}", cursor),
+            Self::ScrollUp(None) => write!(f, "scroll_up"),
+            Self::ScrollDown(cursor) => write!(f, "scroll_down {}", cursor),
+            Self::ScrollLeft => write!(f, "scroll_left"),
+            Self::ScrollRight(cursor) => write!(f, "scroll_right {}", cursor),
+            Self::MakeRoomForDouble(Some(index)) => write!(f, "make_room_for_double {}", index),
+            Self::MakeRoomForDouble(None) => write!(f, "make_room_for_double"),
+            Self::UpdateBox(Some(view)) => write!(f, "update_box {}", view),
+            Self::UpdateBox(None) => write!(f, "update_box"),
+            Self::UpdateBoxKeepingPersonalItem(view) => write!(f, "update_box {}", view),
+        }
+    }
+}
+
+/// Strip the time, level, thread, target, and location that simplelog puts in front of each
+/// message. Returns None if the line doesn't start a new message.
+fn strip_log_prefix(line: &str) -> Option<&str> {
+    let bytes = line.as_bytes();
+    let is_time = bytes.len() > 9
+        && bytes[..8].iter().enumerate().all(|(i, b)| {
+            if i % 3 == 2 {
+                *b == b':'
+            } else {
+                b.is_ascii_digit()
+            }
+        });
+    if !is_time {
+        return None;
+    }
+
+    let (_, mut rest) = line[8..].strip_prefix(" [")?.split_once("] ")?;
+    // thread ID
+    if let Some((id, after)) = rest.strip_prefix('(').and_then(|r| r.split_once(") ")) {
+        if id.bytes().all(|b| b.is_ascii_digit()) {
+            rest = after;
+        }
+    }
+    // target
+    if let Some((target, after)) = rest.split_once(": ") {
+        if !target.is_empty()
+            && target
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b':')
+        {
+            rest = after;
+        }
+    }
+    // source location
+    if let Some((location, after)) = rest.strip_prefix('[').and_then(|r| r.split_once("] ")) {
+        if location
+            .rsplit_once(':')
+            .is_some_and(|(_, l)| l.bytes().all(|b| b.is_ascii_digit()))
+        {
+            rest = after;
+        }
+    }
+
+    Some(rest)
+}
+
+/// A single log message, which may span multiple lines
+struct Record<'a> {
+    line: usize,
+    lines: Vec<&'a str>,
+}
+
+impl Record<'_> {
+    fn dump(&self) -> Result<Option<(usize, ItemBox)>> {
+        let Some(start) = self
+            .lines
+            .iter()
+            .position(|l| l.trim_start().starts_with(BOX_HEADER))
+        else {
+            return self.debug_dump();
+        };
+
+        let line = self.line + start;
+        let item_box = self.lines[start..]
+            .join("\n")
+            .parse()
+            .with_context(|| format!("Invalid box dump at line {}", line))?;
+        Ok(Some((line, item_box)))
+    }
+
+    /// A box dump in the Debug form that older versions of the mod logged
+    fn debug_dump(&self) -> Result<Option<(usize, ItemBox)>> {
+        let Some(start) = self.lines[0].find(DEBUG_BOX_HEADER) else {
+            return Ok(None);
+        };
+
+        let item_box = ItemBox::from_debug(&self.lines[0][start..])
+            .with_context(|| format!("Invalid box dump at line {}", self.line))?;
+        Ok(Some((self.line, item_box)))
+    }
+}
+
+fn records(log: &str) -> Vec<Record<'_>> {
+    let mut records: Vec<Record<'_>> = Vec::new();
+    for (i, line) in log.lines().enumerate() {
+        match strip_log_prefix(line) {
+            Some(message) => records.push(Record {
+                line: i + 1,
+                lines: vec![message],
+            }),
+            // continuation of a multi-line message
+            None => {
+                if let Some(record) = records.last_mut() {
+                    record.lines.push(line);
+                }
+            }
+        }
+    }
+    records
+}
+
+/// The first point where the replayed box didn't match the box in the log
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Line in the log where the mismatched dump starts
+    pub line: usize,
+    /// Line in the log of the last operation replayed before the dump
+    pub operation_line: usize,
+    /// The last operation replayed before the dump
+    pub operation: String,
+    /// Structural differences from the logged box to the replayed box
+    pub diff: String,
+    /// The box as it appeared in the log
+    pub logged: String,
+    /// The box as it came out of the replay
+    pub replayed: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Box diverged at line {} after {} (line {}): {}",
+            self.line, self.operation, self.operation_line, self.diff
+        )?;
+        writeln!(f, "Logged:\n{}", self.logged)?;
+        write!(f, "Replayed:\n{}", self.replayed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Number of operations replayed
+    pub operations: usize,
+    /// Number of operations that couldn't be replayed and were picked up from the next dump
+    pub skipped: usize,
+    /// Number of dumps the replayed box was checked against
+    pub checks: usize,
+    pub divergence: Option<Divergence>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Replayed {} operations ({} skipped) and checked {} box dumps",
+            self.operations, self.skipped, self.checks
+        )?;
+        match self.divergence {
+            Some(ref divergence) => write!(f, "{}", divergence),
+            None => write!(f, "No divergence found"),
+        }
+    }
+}
+
+/// The ID from the message the box logs when it learns a personal item
+fn parse_personal_item(message: &str) -> Option<i32> {
+    message
+        .trim()
+        .strip_prefix("Item ")?
+        .strip_suffix(" is a personal item")?
+        .parse()
+        .ok()
+}
+
+/// Replay the box operations in a trace-level log and report the first divergence, if any
+pub fn replay(log: &str) -> Result<Report> {
+    let mut report = Report::default();
+    // the box is unknown until the first dump and after any operation we can't replay
+    let mut item_box: Option<ItemBox> = None;
+    // the last operation replayed since the box was last checked
+    let mut pending: Option<(usize, Operation)> = None;
+    // personal items the hooks have learned so far, which the dumps don't show
+    let mut personal_items = Vec::new();
+
+    let records = records(log);
+    for (i, record) in records.iter().enumerate() {
+        if let Some((line, mut logged)) = record.dump()? {
+            if let (Some(replayed), Some((operation_line, operation))) = (&item_box, &pending) {
+                report.checks += 1;
+                let replayed_text = replayed.to_string();
+                let logged_text = logged.to_string();
+                if replayed_text != logged_text {
+                    let diff = logged.snapshot().diff(&replayed.snapshot());
+                    let diff = if diff.is_empty() {
+                        // the contents match, so it must be the view or whether the box is open
+                        String::from("contents match but the view or state differs")
+                    } else {
+                        diff.to_string()
+                    };
+                    report.divergence = Some(Divergence {
+                        line,
+                        operation_line: *operation_line,
+                        operation: operation.to_string(),
+                        diff,
+                        logged: logged_text,
+                        replayed: replayed_text,
+                    });
+                    return Ok(report);
+                }
+            }
+
+            // always continue from the logged box so one problem doesn't cascade into the rest
+            // of the log
+            for id in &personal_items {
+                logged.add_personal_item(*id);
+            }
+            item_box = Some(logged);
+            pending = None;
+            continue;
+        }
+
+        if let Some(id) = parse_personal_item(record.lines[0]) {
+            personal_items.push(id);
+            if let Some(replayed) = item_box.as_mut() {
+                replayed.add_personal_item(id);
+            }
+            continue;
+        }
+
+        let Some(mut operation) = Operation::parse(record.lines[0]) else {
+            continue;
+        };
+        if let Operation::UpdateBox(Some(view)) = &operation {
+            if records
+                .get(i + 1)
+                .is_some_and(|r| r.lines[0].starts_with(KEEPING_PERSONAL_ITEM))
+            {
+                operation = Operation::UpdateBoxKeepingPersonalItem(view.clone());
+            }
+        }
+        // the current version logs the index in the message right after this one
+        if matches!(operation, Operation::MakeRoomForDouble(None))
+            && records
+                .get(i + 1)
+                .and_then(|r| Operation::parse(r.lines[0]))
+                .is_some_and(|o| matches!(o, Operation::MakeRoomForDouble(Some(_))))
+        {
+            continue;
+        }
+        let is_replayed = item_box
+            .as_mut()
+            .is_some_and(|replayed| operation.apply(replayed));
+        if is_replayed {
+            report.operations += 1;
+            pending = Some((record.line, operation));
+        } else {
+            report.skipped += 1;
+            item_box = None;
+            pending = None;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inventory::Item;
+
+    fn log_line(message: &str) -> String {
+        format!(
+            "12:34:56 [TRACE] (1) re0box::hooks: [src/hooks.rs:100] {}\n",
+            message
+        )
+    }
+
+    fn test_box() -> ItemBox {
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(
+            [(6, 1), (180, 1), (55, 7), (32, 15), (14, 3), (4, 7), (3, 5)]
+                .into_iter()
+                .map(|(id, count)| Item::new(id, count))
+                .collect(),
+        );
+        item_box
+    }
+
+    /// Generate a log the way the hooks would by running the operations on a real box
+    fn generate_log(item_box: &mut ItemBox, operations: &[Operation]) -> String {
+        let mut log = String::from("12:34:50 [INFO] Initializing item box mod\n");
+        log += &log_line("load_slot 0");
+        log += &log_line(&item_box.to_string());
+        for operation in operations {
+            let message = match operation {
+                Operation::Open => String::from("Opening item box"),
+                Operation::MakeRoomForDouble(Some(index)) => {
+                    log += &log_line("make_room_for_double");
+                    format!("Making room for two-slot item at {}", index)
+                }
+                _ => operation.to_string(),
+            };
+            log += &log_line(&message);
+            operation.apply(item_box);
+            log += &log_line(&item_box.to_string());
+        }
+        log
+    }
+
+    #[test]
+    fn strip_prefix() {
+        assert_eq!(
+            strip_log_prefix("12:34:56 [TRACE] (1) re0box::hooks: [src/hooks.rs:100] scroll_up 1"),
+            Some("scroll_up 1")
+        );
+        assert_eq!(
+            strip_log_prefix("12:34:56 [INFO] Patching complete"),
+            Some("Patching complete")
+        );
+        assert_eq!(
+            strip_log_prefix("12:34:56 [WARN] View is in an invalid state: oops"),
+            Some("View is in an invalid state: oops")
+        );
+        assert_eq!(strip_log_prefix(">   2  55 x7"), None);
+        assert_eq!(strip_log_prefix(""), None);
+    }
+
+    #[test]
+    fn parse_operations() {
+        assert_eq!(
+            Operation::parse("scroll_down 7"),
+            Some(Operation::ScrollDown(7))
+        );
+        assert_eq!(Operation::parse("scroll_left"), Some(Operation::ScrollLeft));
+        assert_eq!(Operation::parse("Opening item box"), Some(Operation::Open));
+        assert_eq!(
+            Operation::parse("Making room for two-slot item at 5"),
+            Some(Operation::MakeRoomForDouble(Some(5)))
+        );
+        // logged regardless of whether the box opened
+        assert_eq!(Operation::parse("open_box"), None);
+        assert_eq!(Operation::parse("scroll_down"), None);
+
+        // what older versions logged
+        assert_eq!(
+            Operation::parse("make_room_for_double"),
+            Some(Operation::MakeRoomForDouble(None))
+        );
+        assert_eq!(
+            Operation::parse("scroll_up"),
+            Some(Operation::ScrollUp(None))
+        );
+        assert_eq!(
+            Operation::parse("update_box"),
+            Some(Operation::UpdateBox(None))
+        );
+
+        let view = "[Shotgun x1] [<slot two>] [55 x7] [<empty>] [<empty>] [<empty>]";
+        let Some(Operation::UpdateBox(Some(bag))) =
+            Operation::parse(&format!("update_box {}", view))
+        else {
+            panic!("update_box with a view didn't parse");
+        };
+        assert_eq!(bag.to_string(), view);
+    }
+
+    #[test]
+    fn replay_matches() {
+        let mut item_box = test_box();
+        let log = generate_log(
+            &mut item_box,
+            &[
+                Operation::Open,
+                Operation::ScrollDown(7),
+                Operation::ScrollRight(6),
+                Operation::ScrollUp(Some(1)),
+                Operation::ScrollLeft,
+                Operation::MakeRoomForDouble(Some(5)),
+                Operation::Close,
+            ],
+        );
+        let report = replay(&log).unwrap();
+        assert!(report.divergence.is_none(), "{}", report);
+        assert_eq!(report.operations, 7);
+        assert_eq!(report.checks, 7);
+        // the load happened before the first dump
+        assert_eq!(report.skipped, 1);
+    }
+
+    #[test]
+    fn replay_diverges() {
+        let mut item_box = test_box();
+        let log = generate_log(
+            &mut item_box,
+            &[
+                Operation::Open,
+                Operation::ScrollDown(7),
+                Operation::ScrollDown(7),
+            ],
+        );
+        // pretend the second scroll went wrong
+        let lines: Vec<_> = log.lines().collect();
+        let dump_start = lines.iter().rposition(|l| l.contains(BOX_HEADER)).unwrap();
+        let bad_log = log.replacen(
+            "Item box (open, index 4, 10 slots):",
+            "Item box (open, index 2, 10 slots):",
+            1,
+        );
+        assert_ne!(bad_log, log);
+
+        let report = replay(&bad_log).unwrap();
+        let divergence = report.divergence.unwrap();
+        assert_eq!(divergence.line, dump_start + 1);
+        assert_eq!(divergence.operation, "scroll_down 7");
+        assert_eq!(divergence.operation_line, dump_start);
+        assert!(divergence.diff.contains("view moved from 2 to 4"));
+    }
+
+    #[test]
+    fn replay_update() {
+        let mut item_box = test_box();
+        let mut log = generate_log(&mut item_box, &[Operation::Open]);
+        // the game takes an item out of the view before calling update_box
+        item_box.view().items_mut()[2] = Item::empty();
+        log += &log_line(&format!("update_box {}", item_box.view()));
+        item_box.update_from_view(|_, _| true);
+        log += &log_line(&item_box.to_string());
+        log += &log_line("close_box");
+        item_box.close();
+        item_box.organize();
+        log += &log_line(&item_box.to_string());
+
+        let report = replay(&log).unwrap();
+        assert!(report.divergence.is_none(), "{}", report);
+        assert_eq!(report.operations, 3);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.checks, 3);
+
+        // an update that doesn't match the simulated one is a divergence
+        let bad_log = log.replacen(
+            "update_box [Shotgun x1] [<slot two>] [<empty>]",
+            "update_box [Shotgun x1] [<slot two>] [55 x7]",
+            1,
+        );
+        assert_ne!(bad_log, log);
+        let divergence = replay(&bad_log).unwrap().divergence.unwrap();
+        assert!(divergence.operation.starts_with("update_box"));
+    }
+
+    #[test]
+    fn replay_new_game() {
+        let mut item_box = test_box();
+        let mut log = generate_log(&mut item_box, &[Operation::Open, Operation::Close]);
+        // with a shared box, carried-over items, or starting items, a new game doesn't start empty
+        log += &log_line("new_game");
+        item_box.set_contents(vec![Item::new(31, 2), Item::new(6, 1), Item::slot_two()]);
+        item_box.organize();
+        log += &log_line(&item_box.to_string());
+        log += &log_line("Opening item box");
+        item_box.open();
+        log += &log_line(&item_box.to_string());
+
+        let report = replay(&log).unwrap();
+        assert!(report.divergence.is_none(), "{}", report);
+        assert_eq!(report.operations, 3);
+        assert_eq!(report.skipped, 2);
+        assert_eq!(report.checks, 3);
+    }
+
+    #[test]
+    fn replay_personal_items() {
+        let mut item_box = test_box();
+        let mut log = generate_log(&mut item_box, &[Operation::Open]);
+        log += &log_line("Item 120 is a personal item");
+        item_box.add_personal_item(120);
+
+        // the player gets their personal item back
+        item_box.view().items_mut()[2] = Item::new(120, 1);
+        log += &log_line(&format!("update_box {}", item_box.view()));
+        item_box.update_from_view(|_, _| true);
+        log += &log_line(&item_box.to_string());
+
+        // the player can't take it back, so the box keeps it
+        item_box.view().items_mut()[3] = Item::new(120, 1);
+        log += &log_line(&format!("update_box {}", item_box.view()));
+        item_box.update_from_view(|_, _| false);
+        log += &log_line(&format!(
+            "{} 120 x1 in the box because the player can't take it back",
+            KEEPING_PERSONAL_ITEM
+        ));
+        log += &log_line(&item_box.to_string());
+        assert_eq!(item_box.get_contents()[3], Item::new(120, 1));
+
+        let report = replay(&log).unwrap();
+        assert!(report.divergence.is_none(), "{}", report);
+        assert_eq!(report.operations, 3);
+        assert_eq!(report.checks, 3);
+    }
+
+    #[test]
+    fn replay_old_log() {
+        // older versions didn't log the view with update_box, the index with
+        // make_room_for_double, or the cursor with scroll_up, and only dumped the box in Debug
+        // form when it was in an invalid state
+        let mut item_box = test_box();
+        item_box.open();
+        let mut log = String::from("12:34:50 [INFO] Initializing item box mod\n");
+        log += &log_line(&format!(
+            "View is in an invalid state after opening: {:?}",
+            item_box
+        ));
+        log += &log_line("scroll_down 7");
+        navigate(&mut item_box, 7, Direction::Down);
+        log += &log_line("scroll_up");
+        navigate(&mut item_box, 0, Direction::Up);
+        log += &log_line(&format!(
+            "View is in an invalid state after scrolling: {:?}",
+            item_box
+        ));
+        log += &log_line("make_room_for_double");
+        log += &log_line("update_box");
+        log += &log_line(&format!(
+            "View is in an invalid state after updating: {:?}",
+            item_box
+        ));
+
+        let report = replay(&log).unwrap();
+        assert!(report.divergence.is_none(), "{}", report);
+        assert_eq!(report.operations, 2);
+        assert_eq!(report.checks, 1);
+        // the box has to be picked up again after each of these
+        assert_eq!(report.skipped, 2);
+    }
+
+    #[test]
+    fn invalid_dump() {
+        let log = log_line("Item box (open, index 0, 2 slots):\n>   0  nonsense");
+        assert!(replay(&log).is_err());
+    }
+}
@@ -0,0 +1,1231 @@
+use std::io::{Cursor, Seek, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use binrw::{binrw, BinRead, BinReaderExt, BinWrite};
+
+use super::catalog;
+use super::inventory::{Item, ItemBox};
+
+pub const NUM_SAVE_SLOTS: usize = 20;
+pub const MAGIC: &[u8] = b"IBOX";
+/// Size of the game's save file in the supported game versions. This is the size of the 20 save
+/// slots plus, presumably, a few hundred bytes of header/metadata. Each game version has its own
+/// save_size in case an update changes it.
+pub const UNMODDED_SAVE_SIZE: usize = 2337008;
+/// Follows MAGIC in versioned trailers. In the original layout, this is where the first slot's item
+/// count would go, and no box can hold this many items. Versions of the mod that only know the
+/// original layout (0.5.2 and earlier) read it as exactly that, so they can't read versioned
+/// trailers; downgrading isn't supported.
+const VERSIONED_MARKER: u32 = u32::MAX;
+/// Only changes when the trailer changes in a way older versions of the mod can't handle. New kinds
+/// of data go in new sections instead, which older versions skip and write back unchanged.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Each slot gets its own section, and therefore its own checksum, so damage to one slot doesn't
+/// affect the others. The last byte is the slot index.
+const SLOT_SECTION: [u8; 3] = *b"SLT";
+const SCOPE_SECTION: [u8; 4] = *b"SCOP";
+/// A fingerprint of each slot's vanilla save data as of when the box data was written
+const FINGERPRINT_SECTION: [u8; 4] = *b"FPRT";
+/// Which slots were saved while the mod was disabled, as a bitmask
+const FROZEN_SECTION: [u8; 4] = *b"FRZN";
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+struct ItemVec {
+    #[bw(calc = items.len() as u32)]
+    count: u32,
+    #[br(count = count)]
+    items: Vec<Item>,
+}
+
+impl ItemVec {
+    pub const fn new() -> Self {
+        Self { items: Vec::new() }
+    }
+}
+
+/// Which saves share a box
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BoxScope {
+    /// Each save slot has its own box
+    #[default]
+    Slot,
+    /// One box is shared by every save slot
+    Global,
+    /// Every save made from the same new game shares a box
+    Playthrough,
+}
+
+impl FromStr for BoxScope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "slot" => Self::Slot,
+            "global" => Self::Global,
+            "playthrough" => Self::Playthrough,
+            _ => bail!("Unknown box scope {:?}", s),
+        })
+    }
+}
+
+/// Parse a comma-separated list of item names or IDs
+fn parse_item_ids(s: &str) -> Result<Vec<i32>> {
+    s.split(',')
+        .map(|name| {
+            let name = name.trim();
+            name.parse()
+                .ok()
+                .or_else(|| catalog::item_id(name))
+                .ok_or_else(|| anyhow!("Unknown item {:?}", name))
+        })
+        .collect()
+}
+
+/// The items that are needed to progress: the ones in the catalog, plus any the user lists
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyItems(Vec<i32>);
+
+impl KeyItems {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn contains(&self, id: i32) -> bool {
+        catalog::is_key_item(id) || self.0.contains(&id)
+    }
+}
+
+impl FromStr for KeyItems {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim().is_empty() {
+            return Ok(Self::new());
+        }
+        Ok(Self(parse_item_ids(s)?))
+    }
+}
+
+/// What to carry over from the last box into a new game
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Carryover {
+    /// New games start with an empty box
+    #[default]
+    Nothing,
+    Everything,
+    /// Everything except key items
+    NonKeyItems,
+    /// Only the items with these IDs
+    Items(Vec<i32>),
+}
+
+impl Carryover {
+    /// Pick out the items to carry over from a box
+    pub fn apply(&self, items: &[Item], key_items: &KeyItems) -> Vec<Item> {
+        let mut carried = Vec::with_capacity(items.len());
+        let mut keep_slot_two = false;
+        for item in items {
+            if item.is_slot_two() {
+                // slot two goes wherever the first slot of its item goes
+                if keep_slot_two {
+                    carried.push(item.clone());
+                }
+                keep_slot_two = false;
+                continue;
+            }
+
+            let keep = !item.is_empty()
+                && match self {
+                    Self::Nothing => false,
+                    Self::Everything => true,
+                    Self::NonKeyItems => !key_items.contains(item.id()),
+                    Self::Items(ids) => ids.contains(&item.id()),
+                };
+            keep_slot_two = keep && item.is_two_slot_item();
+            if keep {
+                carried.push(item.clone());
+            }
+        }
+        carried
+    }
+}
+
+impl FromStr for Carryover {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "none" => Self::Nothing,
+            "all" => Self::Everything,
+            "nonkey" => Self::NonKeyItems,
+            // anything else is a list of item names or IDs
+            _ => Self::Items(parse_item_ids(s)?),
+        })
+    }
+}
+
+/// Items to put in the box at the start of every new game
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StartingBox(Vec<Item>);
+
+impl StartingBox {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.0
+    }
+}
+
+impl FromStr for StartingBox {
+    type Err = Error;
+
+    /// Parse a comma-separated list of item names or IDs with counts like "Shotgun x7, 31 x2"
+    fn from_str(s: &str) -> Result<Self> {
+        let mut items = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let item: Item = entry.parse()?;
+            if item.is_empty() || item.is_slot_two() || item.count() <= 0 {
+                bail!("{:?} is not a valid starting item", entry);
+            }
+            let is_two_slot_item = item.is_two_slot_item();
+            items.push(item);
+            if is_two_slot_item {
+                items.push(Item::slot_two());
+            }
+        }
+
+        // the box rearranges a layout it doesn't accept when it's loaded, so check now whether
+        // that will happen and use the layout the player will actually get
+        let mut item_box = ItemBox::new();
+        item_box.set_contents(items.clone());
+        item_box.organize();
+        let mut organized = item_box.get_contents().to_vec();
+        while organized.last().is_some_and(Item::is_empty) {
+            organized.pop();
+        }
+        if organized != items {
+            log::warn!(
+                "Starting box {:?} doesn't fit the box's layout, so the box will rearrange it",
+                s
+            );
+            items = organized;
+        }
+        Ok(Self(items))
+    }
+}
+
+#[binrw]
+#[derive(Debug, Default, Clone)]
+struct RunBox {
+    id: u32,
+    items: ItemVec,
+}
+
+/// The boxes for the global and playthrough scopes. Each slot's own box is still kept in its slot
+/// section, so damage here only affects the shared boxes.
+#[binrw]
+#[derive(Debug, Default)]
+struct ScopeSection {
+    global: ItemVec,
+    slot_runs: [u32; NUM_SAVE_SLOTS],
+    #[bw(calc = runs.len() as u32)]
+    num_runs: u32,
+    #[br(count = num_runs)]
+    runs: Vec<RunBox>,
+}
+
+/// The header of a versioned trailer, which comes right after MAGIC
+#[binrw]
+#[derive(Debug)]
+struct Header {
+    #[br(assert(marker == VERSIONED_MARKER))]
+    marker: u32,
+    format_version: u32,
+    mod_version: [u16; 3],
+    /// Length of the whole trailer, including MAGIC
+    total_length: u32,
+    num_sections: u32,
+}
+
+impl Header {
+    const SIZE: usize = size_of::<u32>() // marker
+        + size_of::<u32>() // format_version
+        + size_of::<[u16; 3]>() // mod_version
+        + size_of::<u32>() // total_length
+        + size_of::<u32>(); // num_sections
+}
+
+/// A tagged, length-prefixed chunk of box data
+#[binrw]
+#[derive(Debug, Clone)]
+struct Section {
+    id: [u8; 4],
+    #[bw(calc = data.len() as u32)]
+    length: u32,
+    checksum: u32,
+    #[br(count = length)]
+    data: Vec<u8>,
+}
+
+impl Section {
+    /// Size of the id, length, and checksum that come before the data
+    const HEADER_SIZE: usize = size_of::<[u8; 4]>() + size_of::<u32>() + size_of::<u32>();
+
+    fn new<T>(id: [u8; 4], value: &T) -> Result<Self>
+    where
+        T: for<'a> BinWrite<Args<'a> = ()>,
+    {
+        let mut writer = Cursor::new(Vec::new());
+        value.write_le(&mut writer)?;
+        let data = writer.into_inner();
+        Ok(Self {
+            id,
+            checksum: crc32fast::hash(&data),
+            data,
+        })
+    }
+
+    fn parse<T>(&self) -> Result<T>
+    where
+        T: for<'a> BinRead<Args<'a> = ()>,
+    {
+        let mut reader = Cursor::new(&self.data);
+        let value = reader.read_le()?;
+        if (reader.position() as usize) != self.data.len() {
+            bail!(
+                "Section {} has {} bytes of unexpected data",
+                self.name(),
+                self.data.len() - reader.position() as usize
+            );
+        }
+        Ok(value)
+    }
+
+    fn name(&self) -> String {
+        String::from_utf8_lossy(&self.id).into_owned()
+    }
+
+    /// Size of the section as written, including its header
+    fn size(&self) -> usize {
+        Self::HEADER_SIZE + self.data.len()
+    }
+}
+
+fn mod_version() -> [u16; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+    ]
+}
+
+/// The box data that goes in the save file alongside the game's own save data
+#[derive(Debug)]
+pub struct SaveData {
+    scope: BoxScope,
+    // each slot's own box is always kept up to date, whatever the scope, so changing the scope
+    // later (or going back to an older version of the mod) still finds a sensible box in each slot
+    boxes: [ItemVec; NUM_SAVE_SLOTS],
+    global: ItemVec,
+    // the playthrough each slot was saved from. 0 means the slot isn't part of a playthrough yet.
+    slot_runs: [u32; NUM_SAVE_SLOTS],
+    runs: Vec<RunBox>,
+    current_run: u32,
+    // box data we couldn't read. while we have this, we write it back unchanged instead of our own
+    // data so the next save doesn't destroy anything that might still be recoverable.
+    damaged_trailer: Option<Vec<u8>>,
+    fingerprints: Option<[u32; NUM_SAVE_SLOTS]>,
+    // slots that were saved while the mod was disabled, whose boxes were left as they were
+    frozen_slots: u32,
+    // sections from a different version of the mod that we don't understand. we write them back as
+    // they were so that version doesn't lose its data.
+    unknown_sections: Vec<Section>,
+}
+
+impl SaveData {
+    pub const fn new() -> Self {
+        Self {
+            scope: BoxScope::Slot,
+            boxes: [const { ItemVec::new() }; NUM_SAVE_SLOTS],
+            global: ItemVec::new(),
+            slot_runs: [0; NUM_SAVE_SLOTS],
+            runs: Vec::new(),
+            current_run: 0,
+            damaged_trailer: None,
+            fingerprints: None,
+            frozen_slots: 0,
+            unknown_sections: Vec::new(),
+        }
+    }
+
+    pub fn scope(&self) -> BoxScope {
+        self.scope
+    }
+
+    pub fn set_scope(&mut self, scope: BoxScope) {
+        self.scope = scope;
+    }
+
+    fn run_mut(&mut self, id: u32) -> Option<&mut RunBox> {
+        self.runs.iter_mut().find(|r| r.id == id)
+    }
+
+    fn start_run(&mut self, items: Vec<Item>) -> u32 {
+        let id = self
+            .runs
+            .iter()
+            .map(|r| r.id)
+            .chain(self.slot_runs.iter().copied())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        log::debug!("Starting playthrough {}", id);
+        self.runs.push(RunBox {
+            id,
+            items: ItemVec { items },
+        });
+        id
+    }
+
+    /// The box to start a new game with
+    pub fn new_game(&mut self) -> Vec<Item> {
+        match self.scope {
+            BoxScope::Slot => Vec::new(),
+            BoxScope::Global => self.global.items.clone(),
+            BoxScope::Playthrough => {
+                self.current_run = self.start_run(Vec::new());
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn save_slot(&mut self, index: usize, items: &[Item]) {
+        self.frozen_slots &= !(1 << index);
+        self.boxes[index].items = Vec::from(items);
+        match self.scope {
+            BoxScope::Slot => (),
+            BoxScope::Global => self.global.items = Vec::from(items),
+            BoxScope::Playthrough => {
+                let id = self.current_run;
+                let run = match self.run_mut(id) {
+                    Some(run) => run,
+                    // we haven't seen a new game or a load yet, so start a run from here
+                    None => {
+                        self.current_run = self.start_run(Vec::new());
+                        self.runs.last_mut().unwrap()
+                    }
+                };
+                run.items.items = Vec::from(items);
+                self.slot_runs[index] = run.id;
+            }
+        }
+    }
+
+    pub fn load_slot(&mut self, index: usize) -> Vec<Item> {
+        let slot_box = self.boxes[index].items.clone();
+        match self.scope {
+            BoxScope::Slot => slot_box,
+            // the shared box doesn't depend on which slot we load
+            BoxScope::Global => self.global.items.clone(),
+            BoxScope::Playthrough => {
+                let id = self.slot_runs[index];
+                if let Some(run) = self.run_mut(id) {
+                    let items = run.items.items.clone();
+                    self.current_run = id;
+                    return items;
+                }
+
+                // this slot was saved before playthroughs were tracked, so it becomes the start of
+                // a new one
+                self.current_run = self.start_run(slot_box.clone());
+                self.slot_runs[index] = self.current_run;
+                slot_box
+            }
+        }
+    }
+
+    /// Load an empty box for a slot whose box can't be trusted. With the playthrough scope, the
+    /// slot starts a new playthrough, so the box of the one it was part of is left alone.
+    pub fn load_empty_slot(&mut self, index: usize) -> Vec<Item> {
+        if self.scope == BoxScope::Playthrough {
+            self.current_run = self.start_run(Vec::new());
+            self.slot_runs[index] = self.current_run;
+        }
+        Vec::new()
+    }
+
+    /// The box that loading the given slot would give us, without actually loading it
+    pub fn peek_slot(&self, index: usize) -> Vec<Item> {
+        match self.scope {
+            BoxScope::Slot => self.boxes[index].items.clone(),
+            BoxScope::Global => self.global.items.clone(),
+            BoxScope::Playthrough => {
+                let id = self.slot_runs[index];
+                self.runs
+                    .iter()
+                    .find(|r| r.id == id)
+                    .map_or(&self.boxes[index], |r| &r.items)
+                    .items
+                    .clone()
+            }
+        }
+    }
+
+    /// Leave the box in a slot as it is, but remember that the slot was saved while the mod was
+    /// disabled
+    pub fn freeze_slot(&mut self, index: usize) {
+        self.frozen_slots |= 1 << index;
+    }
+
+    /// Whether the slot was last saved while the mod was disabled
+    pub fn is_frozen(&self, index: usize) -> bool {
+        self.frozen_slots & (1 << index) != 0
+    }
+
+    /// Forget the box in a slot, for when the game is saved with the mod disabled
+    pub fn clear_slot(&mut self, index: usize) {
+        self.frozen_slots &= !(1 << index);
+        self.boxes[index].items.clear();
+        self.slot_runs[index] = 0;
+    }
+
+    /// Replace the box in a slot with the one from other box data, such as a backup, for when the
+    /// slot's own box data is damaged
+    pub fn recover_slot(&mut self, index: usize, other: &SaveData) {
+        self.boxes[index] = other.boxes[index].clone();
+    }
+
+    pub fn clear(&mut self) {
+        let scope = self.scope;
+        *self = Self::new();
+        self.scope = scope;
+    }
+
+    fn scope_section(&self) -> ScopeSection {
+        // playthroughs that no slot belongs to anymore can't be loaded, so there's no need to keep
+        // them
+        let runs = self
+            .runs
+            .iter()
+            .filter(|r| self.slot_runs.contains(&r.id))
+            .cloned()
+            .collect();
+        ScopeSection {
+            global: self.global.clone(),
+            slot_runs: self.slot_runs,
+            runs,
+        }
+    }
+
+    pub fn write<W: Write + Seek>(&self, writer: &mut W) -> Result<()> {
+        let mut sections = Vec::with_capacity(NUM_SAVE_SLOTS + 1);
+        for (index, items) in self.boxes.iter().enumerate() {
+            let [a, b, c] = SLOT_SECTION;
+            sections.push(Section::new([a, b, c, index as u8], items)?);
+        }
+        sections.push(Section::new(SCOPE_SECTION, &self.scope_section())?);
+        if let Some(ref fingerprints) = self.fingerprints {
+            sections.push(Section::new(FINGERPRINT_SECTION, fingerprints)?);
+        }
+        if self.frozen_slots != 0 {
+            sections.push(Section::new(FROZEN_SECTION, &self.frozen_slots)?);
+        }
+        sections.extend(self.unknown_sections.iter().cloned());
+        let total_length =
+            MAGIC.len() + Header::SIZE + sections.iter().map(Section::size).sum::<usize>();
+
+        MAGIC.write(writer)?;
+        Header {
+            marker: VERSIONED_MARKER,
+            format_version: FORMAT_VERSION,
+            mod_version: mod_version(),
+            total_length: total_length as u32,
+            num_sections: sections.len() as u32,
+        }
+        .write_le(writer)?;
+        sections.write_le(writer)?;
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        if let Some(ref trailer) = self.damaged_trailer {
+            return Ok(trailer.clone());
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        self.write(&mut writer)?;
+        Ok(writer.into_inner())
+    }
+
+    /// Record the fingerprints of the vanilla save slots that the box data is being saved with,
+    /// returning the ones they replace
+    pub fn set_fingerprints(
+        &mut self,
+        fingerprints: Option<[u32; NUM_SAVE_SLOTS]>,
+    ) -> Option<[u32; NUM_SAVE_SLOTS]> {
+        std::mem::replace(&mut self.fingerprints, fingerprints)
+    }
+
+    /// The fingerprint of the given slot's vanilla save data when its box was saved, if known
+    pub fn fingerprint(&self, index: usize) -> Option<u32> {
+        self.fingerprints.map(|f| f[index])
+    }
+
+    /// Find the slot that was saved with the given fingerprint. If more than one slot was, we can't
+    /// tell which box goes with it.
+    pub fn find_fingerprint(&self, fingerprint: u32) -> Option<usize> {
+        let mut matches = self
+            .fingerprints?
+            .into_iter()
+            .enumerate()
+            .filter(|(_, f)| *f == fingerprint)
+            .map(|(index, _)| index);
+        match (matches.next(), matches.next()) {
+            (Some(index), None) => Some(index),
+            _ => None,
+        }
+    }
+
+    /// Check that box data we wrote reads back as exactly the boxes we have
+    pub fn verify(&self, buf: &[u8]) -> Result<()> {
+        let mut check = Self::new();
+        check.set_scope(self.scope);
+        let damaged_slots = check.read(buf)?;
+        if !damaged_slots.is_empty() {
+            bail!("Box data for slots {:?} is damaged", damaged_slots);
+        }
+        for index in 0..NUM_SAVE_SLOTS {
+            if check.boxes[index].items != self.boxes[index].items
+                || check.peek_slot(index) != self.peek_slot(index)
+            {
+                bail!("Box for slot {} doesn't match after reading it back", index);
+            }
+        }
+        if check.global.items != self.global.items {
+            bail!("Global box doesn't match after reading it back");
+        }
+        if check.to_bytes()? != buf {
+            bail!("Box data changes when it's read back and written again");
+        }
+        Ok(())
+    }
+
+    /// Hold on to box data that couldn't be read so it gets written back as-is. The boxes we
+    /// already have are left alone.
+    pub fn protect(&mut self, buf: &[u8]) {
+        self.damaged_trailer = Some(Vec::from(buf));
+    }
+
+    /// Whether we're holding on to damaged box data instead of saving our own
+    pub fn is_protected(&self) -> bool {
+        self.damaged_trailer.is_some()
+    }
+
+    /// Read the box data that follows the game's own save data. Returns the indexes of any slots
+    /// whose box was damaged.
+    pub fn read(&mut self, buf: &[u8]) -> Result<Vec<usize>> {
+        let mut reader = Cursor::new(buf);
+        if reader.read_le::<[u8; 4]>()? != MAGIC {
+            // something weird has happened
+            return Err(anyhow!(
+                "Save file appears to be modded but box data was not correct"
+            ));
+        }
+
+        if reader.read_le::<u32>()? == VERSIONED_MARKER {
+            reader.set_position(MAGIC.len() as u64);
+            self.read_versioned(reader)
+        } else {
+            reader.set_position(MAGIC.len() as u64);
+            self.read_v0(reader)
+        }
+    }
+
+    /// Read the original trailer layout, which is just the boxes
+    fn read_v0(&mut self, mut reader: Cursor<&[u8]>) -> Result<Vec<usize>> {
+        let boxes = reader.read_le()?;
+        self.clear();
+        self.boxes = boxes;
+        Ok(Vec::new())
+    }
+
+    fn read_versioned(&mut self, mut reader: Cursor<&[u8]>) -> Result<Vec<usize>> {
+        let header: Header = reader.read_le()?;
+        if header.format_version > FORMAT_VERSION {
+            bail!(
+                "Box data is format version {}, written by mod version {}, but this version only supports up to format version {}",
+                header.format_version,
+                header.mod_version.map(|v| v.to_string()).join("."),
+                FORMAT_VERSION
+            );
+        }
+        let total_length = header.total_length as usize;
+        if total_length > reader.get_ref().len() {
+            bail!(
+                "Box data is truncated: expected {} bytes but found {}",
+                total_length,
+                reader.get_ref().len()
+            );
+        }
+        let mut reader = Cursor::new(&reader.get_ref()[..total_length]);
+        reader.set_position((MAGIC.len() + Header::SIZE) as u64);
+
+        let mut boxes: [Option<ItemVec>; NUM_SAVE_SLOTS] = [const { None }; NUM_SAVE_SLOTS];
+        let mut damaged = [false; NUM_SAVE_SLOTS];
+        let mut scope_section = None;
+        let mut fingerprints = None;
+        let mut frozen_slots = 0;
+        let mut unknown_sections = Vec::new();
+        for _ in 0..header.num_sections {
+            let section: Section = reader.read_le()?;
+            let is_intact = crc32fast::hash(&section.data) == section.checksum;
+            if !is_intact {
+                log::warn!("Checksum mismatch in box data section {}", section.name());
+            }
+            match section.id {
+                [a, b, c, index]
+                    if [a, b, c] == SLOT_SECTION && (index as usize) < NUM_SAVE_SLOTS =>
+                {
+                    let index = index as usize;
+                    match is_intact.then(|| section.parse::<ItemVec>()) {
+                        Some(Ok(items)) => boxes[index] = Some(items),
+                        Some(Err(e)) => {
+                            log::warn!("Box data for slot {} is unreadable: {}", index, e);
+                            damaged[index] = true;
+                        }
+                        None => damaged[index] = true,
+                    }
+                }
+                // the scope data is only a convenience; every slot still has its own box
+                SCOPE_SECTION if !is_intact => (),
+                SCOPE_SECTION => scope_section = Some(section.parse()?),
+                // without fingerprints, we just can't check whether boxes match their slots
+                FINGERPRINT_SECTION if !is_intact => (),
+                FINGERPRINT_SECTION => fingerprints = Some(section.parse()?),
+                // the flags are only informational
+                FROZEN_SECTION if !is_intact => (),
+                FROZEN_SECTION => frozen_slots = section.parse()?,
+                _ => {
+                    log::info!(
+                        "Keeping unknown box data section {} ({} bytes) as-is",
+                        section.name(),
+                        section.data.len()
+                    );
+                    unknown_sections.push(section);
+                }
+            }
+        }
+        if (reader.position() as usize) != total_length {
+            bail!("Box data length does not match its sections");
+        }
+
+        // a damaged slot keeps whatever we already had for it, which is nothing unless the save was
+        // previously loaded successfully
+        let old_boxes =
+            std::mem::replace(&mut self.boxes, [const { ItemVec::new() }; NUM_SAVE_SLOTS]);
+        self.clear();
+        let mut damaged_slots = Vec::new();
+        for (index, old_items) in old_boxes.into_iter().enumerate() {
+            if damaged[index] {
+                if old_items.items.is_empty() {
+                    log::warn!(
+                        "Box data for slot {} is damaged; the box will be empty",
+                        index
+                    );
+                } else {
+                    log::warn!(
+                        "Box data for slot {} is damaged; keeping the box from earlier in this session",
+                        index
+                    );
+                }
+                self.boxes[index] = old_items;
+                damaged_slots.push(index);
+                continue;
+            }
+            if let Some(items) = boxes[index].take() {
+                self.boxes[index] = items;
+            }
+        }
+        if let Some(section) = scope_section {
+            self.set_scope_section(section);
+        }
+        self.fingerprints = fingerprints;
+        self.frozen_slots = frozen_slots;
+        self.unknown_sections = unknown_sections;
+        Ok(damaged_slots)
+    }
+
+    fn set_scope_section(&mut self, section: ScopeSection) {
+        self.global = section.global;
+        self.slot_runs = section.slot_runs;
+        self.runs = section.runs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(ids: &[i32]) -> Vec<Item> {
+        ids.iter().map(|id| Item::new(*id, 1)).collect()
+    }
+
+    fn save_data(scope: BoxScope) -> SaveData {
+        let mut save_data = SaveData::new();
+        save_data.set_scope(scope);
+        save_data
+    }
+
+    #[test]
+    fn parse_scope() {
+        assert_eq!("slot".parse::<BoxScope>().unwrap(), BoxScope::Slot);
+        assert_eq!(" Global ".parse::<BoxScope>().unwrap(), BoxScope::Global);
+        assert_eq!(
+            "PLAYTHROUGH".parse::<BoxScope>().unwrap(),
+            BoxScope::Playthrough
+        );
+        assert!("run".parse::<BoxScope>().is_err());
+    }
+
+    #[test]
+    fn slot_scope() {
+        let mut save_data = save_data(BoxScope::Slot);
+        assert!(save_data.new_game().is_empty());
+        save_data.save_slot(0, &items(&[31]));
+        save_data.save_slot(1, &items(&[32]));
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+        assert_eq!(save_data.load_slot(1), items(&[32]));
+        assert!(save_data.load_slot(2).is_empty());
+    }
+
+    #[test]
+    fn global_scope() {
+        let mut save_data = save_data(BoxScope::Global);
+        save_data.save_slot(0, &items(&[31]));
+        // every slot and every new game gets the most recently saved box
+        assert_eq!(save_data.load_slot(5), items(&[31]));
+        assert_eq!(save_data.new_game(), items(&[31]));
+        save_data.save_slot(1, &items(&[31, 32]));
+        assert_eq!(save_data.load_slot(0), items(&[31, 32]));
+
+        // each slot still remembers its own box in case the scope changes back
+        save_data.set_scope(BoxScope::Slot);
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+    }
+
+    #[test]
+    fn playthrough_scope() {
+        let mut save_data = save_data(BoxScope::Playthrough);
+        // first run, saved in slots 0 and 1
+        assert!(save_data.new_game().is_empty());
+        save_data.save_slot(0, &items(&[31]));
+        save_data.load_slot(0);
+        save_data.save_slot(1, &items(&[31, 32]));
+
+        // second run, saved in slot 2
+        save_data.new_game();
+        save_data.save_slot(2, &items(&[50]));
+
+        // loading any slot from the first run gets that run's box
+        assert_eq!(save_data.load_slot(0), items(&[31, 32]));
+        save_data.save_slot(3, &items(&[33]));
+        assert_eq!(save_data.load_slot(1), items(&[33]));
+        // and the second run is unaffected
+        assert_eq!(save_data.load_slot(2), items(&[50]));
+    }
+
+    #[test]
+    fn playthrough_from_old_slot() {
+        let mut save_data = save_data(BoxScope::Slot);
+        save_data.save_slot(0, &items(&[31]));
+        save_data.save_slot(1, &items(&[32]));
+
+        // slots from before the scope changed each start their own playthrough
+        save_data.set_scope(BoxScope::Playthrough);
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+        save_data.save_slot(2, &items(&[31, 33]));
+        assert_eq!(save_data.load_slot(1), items(&[32]));
+        assert_eq!(save_data.load_slot(0), items(&[31, 33]));
+    }
+
+    #[test]
+    fn clear_slot() {
+        let mut save_data = save_data(BoxScope::Playthrough);
+        save_data.new_game();
+        save_data.save_slot(0, &items(&[31]));
+        save_data.clear_slot(0);
+        // the slot has to start a new playthrough now
+        assert!(save_data.load_slot(0).is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut save_data = save_data(BoxScope::Playthrough);
+        save_data.new_game();
+        save_data.save_slot(0, &items(&[31]));
+        // a run that never got saved shouldn't be written
+        save_data.new_game();
+        let bytes = save_data.to_bytes().unwrap();
+
+        let mut loaded = SaveData::new();
+        loaded.set_scope(BoxScope::Playthrough);
+        loaded.read(&bytes).unwrap();
+        assert_eq!(loaded.runs.len(), 1);
+        assert_eq!(loaded.load_slot(0), items(&[31]));
+        assert_eq!(loaded.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn read_old_saves() {
+        // the original format with only the boxes
+        let mut old = Cursor::new(Vec::new());
+        MAGIC.write(&mut old).unwrap();
+        let mut boxes = [const { ItemVec::new() }; NUM_SAVE_SLOTS];
+        boxes[3].items = items(&[31]);
+        boxes.write_le(&mut old).unwrap();
+
+        let mut save_data = SaveData::new();
+        save_data.read(&old.into_inner()).unwrap();
+        assert_eq!(save_data.load_slot(3), items(&[31]));
+
+        assert!(save_data.read(b"XBOX").is_err());
+    }
+
+    fn versioned_save() -> Vec<u8> {
+        let mut save_data = SaveData::new();
+        save_data.save_slot(0, &items(&[31]));
+        save_data.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn versioned_header() {
+        let bytes = versioned_save();
+        let mut reader = Cursor::new(&bytes[MAGIC.len()..]);
+        let header: Header = reader.read_le().unwrap();
+        assert_eq!(reader.position() as usize, Header::SIZE);
+        let section: Section = reader.read_le().unwrap();
+        assert_eq!(reader.position() as usize, Header::SIZE + section.size());
+        assert_eq!(header.format_version, FORMAT_VERSION);
+        assert_eq!(header.mod_version, mod_version());
+        assert_eq!(header.total_length as usize, bytes.len());
+        assert_eq!(header.num_sections as usize, NUM_SAVE_SLOTS + 1);
+
+        // anything after the trailer is ignored
+        let mut padded = bytes.clone();
+        padded.extend_from_slice(&[0; 16]);
+        let mut save_data = SaveData::new();
+        save_data.read(&padded).unwrap();
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+    }
+
+    /// Change fields in the header of a versioned trailer
+    fn patch_header(bytes: &mut [u8], patch: impl FnOnce(&mut Header)) {
+        let header_bytes = &mut bytes[MAGIC.len()..MAGIC.len() + Header::SIZE];
+        let mut header: Header = Cursor::new(&*header_bytes).read_le().unwrap();
+        patch(&mut header);
+        header.write_le(&mut Cursor::new(header_bytes)).unwrap();
+    }
+
+    /// Find where a section's data starts in a versioned trailer
+    fn section_offset(bytes: &[u8], id: [u8; 4]) -> usize {
+        let mut reader = Cursor::new(bytes);
+        reader.set_position((MAGIC.len() + Header::SIZE) as u64);
+        loop {
+            let section: Section = reader.read_le().unwrap();
+            if section.id == id {
+                return reader.position() as usize - section.data.len();
+            }
+        }
+    }
+
+    #[test]
+    fn versioned_errors() {
+        let bytes = versioned_save();
+        let mut save_data = SaveData::new();
+
+        let error = save_data
+            .read(&bytes[..bytes.len() - 1])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("truncated"), "{}", error);
+
+        let mut newer = bytes.clone();
+        patch_header(&mut newer, |header| {
+            header.format_version = FORMAT_VERSION + 1
+        });
+        let error = save_data.read(&newer).unwrap_err().to_string();
+        assert!(error.contains("format version"), "{}", error);
+
+        // nothing is loaded from a bad trailer
+        save_data.save_slot(0, &items(&[50]));
+        assert!(save_data.read(&newer).is_err());
+        assert_eq!(save_data.load_slot(0), items(&[50]));
+    }
+
+    #[test]
+    fn damaged_slot() {
+        let mut save_data = SaveData::new();
+        save_data.save_slot(0, &items(&[31]));
+        save_data.save_slot(3, &items(&[32]));
+        let mut bytes = save_data.to_bytes().unwrap();
+        let offset = section_offset(&bytes, *b"SLT\x03");
+        bytes[offset + 4] ^= 0xff;
+
+        // the damaged slot keeps the box we already had
+        save_data.save_slot(3, &items(&[33]));
+        assert_eq!(save_data.read(&bytes).unwrap(), vec![3]);
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+        assert_eq!(save_data.load_slot(3), items(&[33]));
+
+        // and is empty if we didn't have one
+        let mut save_data = SaveData::new();
+        assert_eq!(save_data.read(&bytes).unwrap(), vec![3]);
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+        assert!(save_data.load_slot(3).is_empty());
+
+        // damage to the scope data doesn't affect the slots
+        let mut bytes = save_data.to_bytes().unwrap();
+        let offset = section_offset(&bytes, SCOPE_SECTION);
+        bytes[offset] ^= 0xff;
+        assert!(save_data.read(&bytes).unwrap().is_empty());
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+    }
+
+    #[test]
+    fn protect_damaged_trailer() {
+        let mut save_data = SaveData::new();
+        save_data.save_slot(0, &items(&[31]));
+        let mut newer = versioned_save();
+        patch_header(&mut newer, |header| {
+            header.format_version = FORMAT_VERSION + 1
+        });
+
+        assert!(save_data.read(&newer).is_err());
+        save_data.protect(&newer);
+        assert!(save_data.is_protected());
+        // we keep playing with the box we had, but saving writes back the data we couldn't read
+        assert_eq!(save_data.load_slot(0), items(&[31]));
+        save_data.save_slot(0, &items(&[32]));
+        assert_eq!(save_data.to_bytes().unwrap(), newer);
+
+        // a successful read gets us out of protective mode
+        save_data.read(&versioned_save()).unwrap();
+        assert!(!save_data.is_protected());
+        assert_eq!(save_data.to_bytes().unwrap(), versioned_save());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let mut save_data = SaveData::new();
+        assert_eq!(save_data.fingerprint(0), None);
+        let mut fingerprints = [0; NUM_SAVE_SLOTS];
+        for (i, fingerprint) in fingerprints.iter_mut().enumerate() {
+            *fingerprint = i as u32 * 100;
+        }
+        save_data.set_fingerprints(Some(fingerprints));
+        let bytes = save_data.to_bytes().unwrap();
+
+        let mut save_data = SaveData::new();
+        save_data.read(&bytes).unwrap();
+        assert_eq!(save_data.fingerprint(3), Some(300));
+        assert_eq!(save_data.find_fingerprint(500), Some(5));
+        assert_eq!(save_data.find_fingerprint(501), None);
+
+        // slots with the same data (such as empty slots) can't be told apart
+        fingerprints[6] = 500;
+        save_data.set_fingerprints(Some(fingerprints));
+        assert_eq!(save_data.find_fingerprint(500), None);
+
+        // older saves don't have fingerprints
+        save_data.read(&versioned_save()).unwrap();
+        assert_eq!(save_data.fingerprint(3), None);
+    }
+
+    #[test]
+    fn parse_carryover() {
+        assert_eq!("none".parse::<Carryover>().unwrap(), Carryover::Nothing);
+        assert_eq!("All".parse::<Carryover>().unwrap(), Carryover::Everything);
+        assert_eq!(
+            "nonkey".parse::<Carryover>().unwrap(),
+            Carryover::NonKeyItems
+        );
+        assert_eq!(
+            "Shotgun, 31,hookshot".parse::<Carryover>().unwrap(),
+            Carryover::Items(vec![6, 31, 104])
+        );
+        assert!("Shotgun, Rubber Chicken".parse::<Carryover>().is_err());
+    }
+
+    #[test]
+    fn carryover() {
+        // a box with a two-slot item
+        let items = vec![
+            Item::new(6, 7),
+            Item::slot_two(),
+            Item::new(104, 1),
+            Item::empty(),
+            Item::new(31, 2),
+        ];
+        let key_items = KeyItems::default();
+        assert!(Carryover::Nothing.apply(&items, &key_items).is_empty());
+        assert_eq!(
+            Carryover::Everything.apply(&items, &key_items),
+            vec![
+                Item::new(6, 7),
+                Item::slot_two(),
+                Item::new(104, 1),
+                Item::new(31, 2),
+            ]
+        );
+        // leaving out a two-slot item leaves out its second slot too
+        assert_eq!(
+            Carryover::Items(vec![31, 104]).apply(&items, &key_items),
+            vec![Item::new(104, 1), Item::new(31, 2)]
+        );
+        // the hookshot is a known key item, and the user can name others
+        assert_eq!(
+            Carryover::NonKeyItems.apply(&items, &key_items),
+            vec![Item::new(6, 7), Item::slot_two(), Item::new(31, 2)]
+        );
+        assert_eq!(
+            Carryover::NonKeyItems.apply(&items, &"Shotgun".parse().unwrap()),
+            vec![Item::new(31, 2)]
+        );
+    }
+
+    #[test]
+    fn parse_key_items() {
+        assert_eq!("".parse::<KeyItems>().unwrap(), KeyItems::default());
+        let key_items: KeyItems = "31, Shotgun".parse().unwrap();
+        assert!(key_items.contains(31));
+        assert!(key_items.contains(6));
+        assert!(key_items.contains(104));
+        assert!(!key_items.contains(32));
+        assert!("31, Rubber Chicken".parse::<KeyItems>().is_err());
+    }
+
+    #[test]
+    fn peek_slot() {
+        let mut save_data = save_data(BoxScope::Playthrough);
+        save_data.new_game();
+        save_data.save_slot(0, &items(&[31]));
+        save_data.new_game();
+        save_data.save_slot(1, &items(&[32]));
+        assert_eq!(save_data.peek_slot(0), items(&[31]));
+        // peeking doesn't switch playthroughs
+        save_data.save_slot(2, &items(&[33]));
+        assert_eq!(save_data.peek_slot(1), items(&[33]));
+        assert_eq!(save_data.peek_slot(0), items(&[31]));
+    }
+
+    #[test]
+    fn parse_starting_box() {
+        assert_eq!("".parse::<StartingBox>().unwrap(), StartingBox::default());
+        assert_eq!(
+            "6 x7, 31 x2,".parse::<StartingBox>().unwrap().items(),
+            [Item::new(6, 7), Item::slot_two(), Item::new(31, 2)]
+        );
+        // two-slot items have to start on an even index
+        assert_eq!(
+            "31 x2, 6 x7".parse::<StartingBox>().unwrap().items(),
+            [Item::new(6, 7), Item::slot_two(), Item::new(31, 2)]
+        );
+        assert_eq!(
+            "Shotgun x7".parse::<StartingBox>().unwrap().items(),
+            [Item::new(6, 7), Item::slot_two()]
+        );
+        assert!("6".parse::<StartingBox>().is_err());
+        assert!("Rubber Chicken x1".parse::<StartingBox>().is_err());
+        assert!("31 x0".parse::<StartingBox>().is_err());
+        assert!("<slot two>".parse::<StartingBox>().is_err());
+    }
+
+    #[test]
+    fn frozen_slots() {
+        let mut save_data = SaveData::new();
+        save_data.save_slot(0, &items(&[31]));
+        save_data.save_slot(1, &items(&[32]));
+        save_data.freeze_slot(0);
+        save_data.freeze_slot(1);
+        save_data.clear_slot(1);
+        assert!(save_data.is_frozen(0));
+        assert!(!save_data.is_frozen(1));
+
+        let mut loaded = SaveData::new();
+        loaded.read(&save_data.to_bytes().unwrap()).unwrap();
+        assert!(loaded.is_frozen(0));
+        assert_eq!(loaded.load_slot(0), items(&[31]));
+
+        // saving normally unfreezes the slot
+        loaded.save_slot(0, &items(&[33]));
+        assert!(!loaded.is_frozen(0));
+    }
+
+    #[test]
+    fn unknown_sections() {
+        let mut save_data = SaveData::new();
+        save_data.save_slot(0, &items(&[31]));
+        let mut bytes = save_data.to_bytes().unwrap();
+
+        // add sections from some future version of the mod
+        let extra = [
+            Section::new(*b"XTRA", &[1u32, 2, 3]).unwrap(),
+            Section::new(*b"SLT\x20", &0u8).unwrap(),
+        ];
+        for section in &extra {
+            let mut writer = Cursor::new(Vec::new());
+            section.write_le(&mut writer).unwrap();
+            bytes.extend(writer.into_inner());
+        }
+        let total_length = bytes.len() as u32;
+        patch_header(&mut bytes, |header| {
+            header.total_length = total_length;
+            header.num_sections += extra.len() as u32;
+        });
+
+        let mut loaded = SaveData::new();
+        loaded.read(&bytes).unwrap();
+        assert_eq!(loaded.load_slot(0), items(&[31]));
+
+        // they survive our changes
+        loaded.save_slot(1, &items(&[32]));
+        let bytes = loaded.to_bytes().unwrap();
+        for section in &extra {
+            let offset = section_offset(&bytes, section.id);
+            assert_eq!(&bytes[offset..offset + section.data.len()], section.data);
+        }
+
+        // but not a fresh start
+        loaded.clear();
+        let bytes = loaded.to_bytes().unwrap();
+        let mut reader = Cursor::new(&bytes[MAGIC.len()..]);
+        let header: Header = reader.read_le().unwrap();
+        assert_eq!(header.num_sections as usize, NUM_SAVE_SLOTS + 1);
+    }
+
+    #[test]
+    fn verify() {
+        let mut save_data = SaveData::new();
+        save_data.set_scope(BoxScope::Playthrough);
+        save_data.new_game();
+        save_data.save_slot(0, &items(&[31]));
+        save_data.save_slot(1, &items(&[32]));
+        let bytes = save_data.to_bytes().unwrap();
+        save_data.verify(&bytes).unwrap();
+
+        save_data.save_slot(1, &items(&[33]));
+        let error = save_data.verify(&bytes).unwrap_err().to_string();
+        assert!(error.contains("doesn't match"), "{}", error);
+
+        let mut damaged = save_data.to_bytes().unwrap();
+        let offset = section_offset(&damaged, *b"SLT\x00");
+        damaged[offset + 4] ^= 0xff;
+        assert!(save_data.verify(&damaged).is_err());
+        assert!(save_data.verify(&damaged[..damaged.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Headless model of the inventory menu with the box open, so box bugs found in game can be turned
+//! into regression tests.
+//!
+//! The cursor is always on the partner side of the menu, where the box view is displayed. Inputs
+//! are given as a whitespace-separated script like `down down exchange(3) right`, where
+//! `exchange(n)` exchanges the item under the cursor with slot `n` of the character's bag.
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::inventory::{Bag, Item, ItemBox, BAG_SIZE};
+use super::navigation::{navigate, Direction};
+
+const BAG_SIZE_I32: i32 = BAG_SIZE as i32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Move(Direction),
+    Exchange(usize),
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        Ok(match s.as_str() {
+            "up" => Self::Move(Direction::Up),
+            "down" => Self::Move(Direction::Down),
+            "left" => Self::Move(Direction::Left),
+            "right" => Self::Move(Direction::Right),
+            _ => {
+                let Some(index) = s
+                    .strip_prefix("exchange(")
+                    .and_then(|s| s.strip_suffix(')'))
+                else {
+                    bail!("Unknown input {:?}", s);
+                };
+                let index: usize = index.trim().parse()?;
+                if index >= BAG_SIZE {
+                    bail!("Bag index {} is out of range", index);
+                }
+                Self::Exchange(index)
+            }
+        })
+    }
+}
+
+/// An item along with its second slot if it has one
+type Unit = Vec<Item>;
+
+fn units(items: &[Item]) -> Vec<Unit> {
+    let mut units: Vec<Unit> = Vec::new();
+    for item in items.iter().filter(|i| !i.is_empty()) {
+        match units.last_mut() {
+            Some(unit) if item.is_slot_two() && unit.len() == 1 && unit[0].is_two_slot_item() => {
+                unit.push(item.clone())
+            }
+            _ => units.push(vec![item.clone()]),
+        }
+    }
+    units
+}
+
+/// Lay out items the way the game's organize step does: no gaps, and two-slot items always start
+/// in the left column. Returns None if the items don't fit.
+fn organize(units: Vec<Unit>) -> Option<[Item; BAG_SIZE]> {
+    let mut slots: Vec<Item> = Vec::with_capacity(BAG_SIZE);
+    for unit in units {
+        if unit.len() == 2 && slots.len() % 2 == 1 {
+            // a two-slot item can't start in the right column, so the item before it moves after it
+            let previous = slots.pop().unwrap();
+            slots.extend(unit);
+            slots.push(previous);
+        } else {
+            slots.extend(unit);
+        }
+    }
+
+    if slots.len() > BAG_SIZE {
+        return None;
+    }
+    slots.resize_with(BAG_SIZE, Item::empty);
+    slots.try_into().ok()
+}
+
+#[derive(Debug)]
+pub struct Simulator {
+    item_box: ItemBox,
+    bag: Bag,
+    cursor: i32,
+}
+
+impl Simulator {
+    pub fn new(mut item_box: ItemBox, bag: Bag) -> Self {
+        item_box.open();
+        Self {
+            item_box,
+            bag,
+            cursor: 0,
+        }
+    }
+
+    pub fn run(&mut self, script: &str) -> Result<()> {
+        for token in script.split_whitespace() {
+            let input: Input = token.parse()?;
+            self.input(input)
+                .map_err(|e| anyhow!("{} failed: {}\n{}", token, e, self.item_box))?;
+        }
+        Ok(())
+    }
+
+    pub fn input(&mut self, input: Input) -> Result<()> {
+        match input {
+            Input::Move(direction) => self.move_cursor(direction),
+            Input::Exchange(bag_index) => self.exchange(bag_index)?,
+        }
+
+        // wrapping around the edges or exchanging items can leave the cursor on the second half
+        // of a two-slot item, but the game highlights the whole item, so the cursor is effectively
+        // on its first half
+        if self.item_box.view().is_slot_two(self.cursor as usize) {
+            self.cursor -= 1;
+        }
+
+        let violations = self.item_box.check_invariants();
+        if !violations.is_empty() {
+            bail!("Box is invalid after {:?}: {:?}", input, violations);
+        }
+        Ok(())
+    }
+
+    fn move_cursor(&mut self, direction: Direction) {
+        let cursor = self.cursor;
+        let view = self.item_box.view();
+        self.cursor = match direction {
+            Direction::Up if cursor >= 2 => {
+                let new_cursor = cursor - 2;
+                if view.is_slot_two(new_cursor as usize) {
+                    new_cursor - 1
+                } else {
+                    new_cursor
+                }
+            }
+            Direction::Up => navigate(&mut self.item_box, cursor, direction).cursor,
+            Direction::Down => {
+                let navigation = navigate(&mut self.item_box, cursor + 2, direction);
+                if navigation.cursor >= BAG_SIZE_I32 {
+                    // the game refuses to move past the last row
+                    cursor
+                } else {
+                    navigation.cursor
+                }
+            }
+            Direction::Left if cursor >= 1 => {
+                let new_cursor = cursor - 1;
+                if view.is_slot_two(new_cursor as usize) {
+                    new_cursor - 1
+                } else {
+                    new_cursor
+                }
+            }
+            Direction::Left => navigate(&mut self.item_box, -1, direction).cursor,
+            Direction::Right => {
+                // the game skips over the second half of a two-slot item
+                let step = if view.items()[cursor as usize].is_two_slot_item() {
+                    2
+                } else {
+                    1
+                };
+                let new_cursor = cursor + step;
+                if new_cursor >= BAG_SIZE_I32 - 1 {
+                    navigate(&mut self.item_box, new_cursor, direction).cursor
+                } else {
+                    new_cursor
+                }
+            }
+        };
+    }
+
+    fn exchange(&mut self, bag_index: usize) -> Result<()> {
+        let bag_index = if self.bag.is_slot_two(bag_index) {
+            bag_index - 1
+        } else {
+            bag_index
+        };
+        let bag_units = units(self.bag.items());
+        let bag_pos = unit_index(self.bag.items(), bag_index);
+
+        // the game asks how much room there is before exchanging a two-slot item, which is where
+        // the box makes room for it
+        if bag_pos
+            .and_then(|i| bag_units.get(i))
+            .is_some_and(|u| u.len() == 2)
+        {
+            self.item_box.make_room_for_double(self.cursor as usize);
+        }
+
+        let view_index = if self.item_box.view().is_slot_two(self.cursor as usize) {
+            self.cursor as usize - 1
+        } else {
+            self.cursor as usize
+        };
+        let view_units = units(self.item_box.view().items());
+        let view_pos = unit_index(self.item_box.view().items(), view_index);
+
+        let (new_bag, new_view) = swap_units(bag_units, bag_pos, view_units, view_pos);
+        let (Some(new_bag), Some(new_view)) = (organize(new_bag), organize(new_view)) else {
+            bail!("Not enough room to exchange");
+        };
+
+        let equipped = self.bag.equipped_item();
+        *self.bag.items_mut() = new_bag;
+        self.bag.fix_equipped_item(equipped.as_ref());
+        *self.item_box.view().items_mut() = new_view;
+        self.item_box.update_from_view(|_, _| true);
+        Ok(())
+    }
+
+    pub const fn cursor(&self) -> i32 {
+        self.cursor
+    }
+
+    pub fn item_box(&mut self) -> &mut ItemBox {
+        &mut self.item_box
+    }
+
+    pub const fn bag(&self) -> &Bag {
+        &self.bag
+    }
+}
+
+/// Find which unit starts at the given slot, if any
+fn unit_index(items: &[Item], slot: usize) -> Option<usize> {
+    match items.get(slot) {
+        // empty slots don't make units, so count the units before this one
+        Some(item) if !item.is_empty() => Some(units(&items[..slot]).len()),
+        _ => None,
+    }
+}
+
+fn swap_units(
+    mut a: Vec<Unit>,
+    a_pos: Option<usize>,
+    mut b: Vec<Unit>,
+    b_pos: Option<usize>,
+) -> (Vec<Unit>, Vec<Unit>) {
+    let a_unit = a_pos.map(|i| a.remove(i));
+    let b_unit = b_pos.map(|i| b.remove(i));
+    if let Some(unit) = b_unit {
+        a.insert(a_pos.unwrap_or(a.len()).min(a.len()), unit);
+    }
+    if let Some(unit) = a_unit {
+        b.insert(b_pos.unwrap_or(b.len()).min(b.len()), unit);
+    }
+    (a, b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count_items(sim: &mut Simulator) -> usize {
+        let in_bag = sim.bag().items().iter().filter(|i| !i.is_empty()).count();
+        let in_box = sim
+            .item_box()
+            .get_contents()
+            .iter()
+            .filter(|i| !i.is_empty())
+            .count();
+        in_bag + in_box
+    }
+
+    fn numbered_box(count: i32) -> ItemBox {
+        let items: Vec<_> = (1..=count)
+            .map(|i| format!(r#"{{ "item": {}, "count": 1 }}"#, i + 30))
+            .collect();
+        ItemBox::from_json(&format!(r#"{{ "items": [{}] }}"#, items.join(","))).unwrap()
+    }
+
+    /// xorshift, so the random tests are reproducible without pulling in a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn item(&mut self) -> Item {
+            // mostly single items, with the occasional two-slot item
+            const IDS: [i32; 8] = [6, 104, 23, 31, 32, 33, 34, 35];
+            Item::new(IDS[self.below(IDS.len())], self.below(20) as i32)
+        }
+
+        fn items(&mut self, max_units: usize) -> Vec<Item> {
+            let mut items = Vec::new();
+            for _ in 0..self.below(max_units + 1) {
+                let item = self.item();
+                let is_two_slot_item = item.is_two_slot_item();
+                items.push(item);
+                if is_two_slot_item {
+                    items.push(Item::slot_two());
+                }
+            }
+            items
+        }
+    }
+
+    #[test]
+    fn random_inputs() {
+        let mut rng = Rng(0x5eed_0b0c);
+        for round in 0..2000 {
+            let mut item_box = ItemBox::new();
+            item_box.set_contents(rng.items(20));
+            item_box.organize();
+            let bag_items = organize(units(&rng.items(3))).unwrap();
+            let mut sim = Simulator::new(item_box, Bag::with_items(bag_items));
+
+            for step in 0..50 {
+                let input = match rng.below(8) {
+                    0 => Input::Move(Direction::Up),
+                    1 => Input::Move(Direction::Down),
+                    2 => Input::Move(Direction::Left),
+                    3 => Input::Move(Direction::Right),
+                    _ => Input::Exchange(rng.below(BAG_SIZE)),
+                };
+                let before = sim.item_box().to_string();
+                if let Err(e) = sim.input(input) {
+                    // the simulator refuses exchanges that don't fit. anything else means the box
+                    // is in a bad state.
+                    let after = sim.item_box().to_string();
+                    assert!(
+                        e.to_string().contains("Not enough room"),
+                        "round {} step {}: {}\nbefore:\n{}\nafter:\n{}",
+                        round,
+                        step,
+                        e,
+                        before,
+                        after
+                    );
+                    // making room may have left a gap outside the view, which the game's next
+                    // update of the box cleans up
+                    sim.item_box().update_from_view(|_, _| true);
+                    assert_eq!(sim.item_box().check_invariants(), vec![], "{}", after);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_inputs() {
+        assert_eq!(
+            "down".parse::<Input>().unwrap(),
+            Input::Move(Direction::Down)
+        );
+        assert_eq!("Exchange(3)".parse::<Input>().unwrap(), Input::Exchange(3));
+        assert!("exchange(6)".parse::<Input>().is_err());
+        assert!("jump".parse::<Input>().is_err());
+    }
+
+    #[test]
+    fn organize_step() {
+        let layout = organize(units(&[
+            Item::new(55, 7),
+            Item::empty(),
+            Item::new(6, 1),
+            Item::slot_two(),
+        ]))
+        .unwrap();
+        assert_eq!(layout[0], Item::new(6, 1));
+        assert!(layout[1].is_slot_two());
+        assert_eq!(layout[2], Item::new(55, 7));
+        assert!(layout[3].is_empty());
+
+        let too_many = vec![vec![Item::new(55, 1)]; BAG_SIZE + 1];
+        assert!(organize(too_many).is_none());
+    }
+
+    #[test]
+    fn scroll_through_box() {
+        let mut sim = Simulator::new(numbered_box(12), Bag::empty());
+        sim.run("down down down").unwrap();
+        assert_eq!(sim.cursor(), 4);
+        assert_eq!(sim.item_box().snapshot().index(), 2);
+        sim.run("right right").unwrap();
+        assert_eq!(sim.cursor(), 4);
+        assert_eq!(sim.item_box().snapshot().index(), 4);
+        sim.run("down down down").unwrap();
+        // the view stops scrolling when the last row of items is at the top
+        assert_eq!(sim.cursor(), 4);
+        assert_eq!(sim.item_box().snapshot().index(), 10);
+        sim.run("down").unwrap();
+        assert_eq!(sim.cursor(), 4);
+        assert_eq!(sim.item_box().snapshot().index(), 10);
+        sim.run("up up up up up up up").unwrap();
+        assert_eq!(sim.cursor(), 0);
+        assert_eq!(sim.item_box().snapshot().index(), 0);
+        sim.run("left").unwrap();
+        // wraps around at the top
+        assert_eq!(sim.cursor(), 5);
+    }
+
+    #[test]
+    fn exchange_single() {
+        let mut bag_items = [const { Item::empty() }; BAG_SIZE];
+        bag_items[0] = Item::new(4, 9);
+        let mut sim = Simulator::new(numbered_box(8), Bag::with_items(bag_items));
+        let total = count_items(&mut sim);
+        sim.run("down down down exchange(0)").unwrap();
+        assert_eq!(sim.bag().items()[0], Item::new(37, 1));
+        assert!(sim.item_box().get_contents().contains(&Item::new(4, 9)));
+        assert_eq!(count_items(&mut sim), total);
+    }
+
+    #[test]
+    fn deposit_into_empty_slot() {
+        let mut bag_items = [const { Item::empty() }; BAG_SIZE];
+        bag_items[0] = Item::new(4, 9);
+        bag_items[1] = Item::new(3, 5);
+        let mut sim = Simulator::new(numbered_box(3), Bag::with_items(bag_items));
+        sim.run("down right exchange(0)").unwrap();
+        assert_eq!(sim.bag().items()[0], Item::new(3, 5));
+        assert!(sim.bag().items()[1].is_empty());
+        assert_eq!(sim.item_box().get_contents()[3], Item::new(4, 9));
+    }
+
+    #[test]
+    fn exchange_two_slot_into_full_view() {
+        let mut bag_items = [const { Item::empty() }; BAG_SIZE];
+        bag_items[0] = Item::new(6, 1);
+        bag_items[1] = Item::slot_two();
+        let mut sim = Simulator::new(numbered_box(8), Bag::with_items(bag_items));
+        let total = count_items(&mut sim);
+        sim.run("right exchange(0)").unwrap();
+        assert_eq!(sim.bag().items()[0], Item::new(32, 1));
+        assert!(sim.bag().items()[1].is_empty());
+        let contents = sim.item_box().get_contents();
+        let pos = contents.iter().position(|i| i.id() == 6).unwrap();
+        assert_eq!(pos & 1, 0);
+        assert!(contents[pos + 1].is_slot_two());
+        // the shotgun's second slot counts as an item in the box but not in the bag anymore
+        assert_eq!(count_items(&mut sim), total);
+    }
+
+    #[test]
+    fn exchange_two_slot_with_two_slot_at_end() {
+        // the same layout as inventory::tests::make_room_for_double_with_two_slot_at_end
+        let item_box = ItemBox::from_json(
+            r#"{ "items": [
+                { "item": 5, "count": 0 }, { "item": 38, "count": 3 }, { "item": 2 },
+                { "item": 104 }, { "item": 2 }, { "item": 48 }, { "item": 3, "count": 0 },
+                { "item": 36, "count": 6 }, { "item": 35, "count": 16 },
+                { "item": 10, "count": 8 }, { "item": 53 }, { "item": 43 }, { "item": 43 },
+                { "item": 43 }, { "item": 55, "count": 12 }, { "item": 34 },
+                { "item": 39, "count": 12 }, { "item": 14, "count": 3 }, { "item": 53 }
+            ] }"#,
+        )
+        .unwrap();
+        let mut bag_items = [const { Item::empty() }; BAG_SIZE];
+        bag_items[0] = Item::new(6, 1);
+        bag_items[1] = Item::slot_two();
+        bag_items[2] = Item::new(55, 3);
+        let mut sim = Simulator::new(item_box, Bag::with_items(bag_items));
+        let total = count_items(&mut sim);
+        sim.run("down exchange(0)").unwrap();
+        assert!(!sim.item_box().view().is_broken());
+        assert!(sim.bag().items().contains(&Item::new(38, 3)));
+        assert_eq!(count_items(&mut sim), total);
+    }
+}
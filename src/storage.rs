@@ -0,0 +1,1409 @@
+use std::ffi::CStr;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Error, Result};
+use binrw::{binrw, BinReaderExt, BinWrite};
+
+use super::backup::{Backups, BACKUP_DIR_NAME};
+use super::inventory::Item;
+use super::save::{
+    BoxScope, Carryover, KeyItems, SaveData, StartingBox, MAGIC, NUM_SAVE_SLOTS, UNMODDED_SAVE_SIZE,
+};
+use super::vanilla::{VanillaSave, SLOTS_SIZE};
+
+/// Somewhere the game's save file can be written and read back
+pub trait SaveBackend {
+    fn write(&mut self, name: &CStr, buf: &[u8]) -> Result<()>;
+
+    fn read(&mut self, name: &CStr) -> Result<Vec<u8>>;
+}
+
+/// Where box data is stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// Appended to the game's save file
+    #[default]
+    Trailer,
+    /// In a separate file next to the game, leaving the game's save file untouched
+    Sidecar,
+}
+
+impl FromStr for Storage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "trailer" => Self::Trailer,
+            "sidecar" => Self::Sidecar,
+            _ => bail!("Unknown storage {:?}", s),
+        })
+    }
+}
+
+pub const SIDECAR_FILE_NAME: &str = "re0box.sav";
+
+/// How many saves' worth of box data the sidecar keeps
+const MAX_SIDECAR_ENTRIES: usize = 10;
+
+#[binrw]
+#[derive(Debug, Clone, PartialEq)]
+struct SidecarEntry {
+    // a checksum of the game's save data that the box data was saved with
+    save_checksum: u32,
+    #[bw(calc = data.len() as u32)]
+    data_len: u32,
+    #[br(count = data_len)]
+    data: Vec<u8>,
+}
+
+/// A file holding the box data for the most recent saves, keyed by a checksum of the game's save
+/// data each one was saved with. Unlike the save file's name, the checksum is something we can
+/// work out when the game loads.
+#[binrw]
+#[brw(little, magic = b"IBSC")]
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Sidecar {
+    #[bw(calc = entries.len() as u32)]
+    num_entries: u32,
+    // most recently saved last
+    #[br(count = num_entries)]
+    entries: Vec<SidecarEntry>,
+}
+
+impl Sidecar {
+    /// Load the sidecar file. A missing file is the same as an empty one.
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read(path) {
+            Ok(buf) => Ok(Cursor::new(buf).read_le()?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        // write to a temporary file first so a failed write doesn't take the old data with it
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = Cursor::new(Vec::new());
+        self.write(&mut writer)?;
+        std::fs::write(&tmp_path, writer.into_inner())?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// The box data that was saved with the game's save data that has the given checksum
+    pub fn get(&self, save_checksum: u32) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|e| e.save_checksum == save_checksum)
+            .map(|e| e.data.as_slice())
+    }
+
+    /// The box data that was saved most recently
+    pub fn latest(&self) -> Option<&[u8]> {
+        self.entries.last().map(|e| e.data.as_slice())
+    }
+
+    pub fn set(&mut self, save_checksum: u32, data: Vec<u8>) {
+        self.entries.retain(|e| e.save_checksum != save_checksum);
+        self.entries.push(SidecarEntry {
+            save_checksum,
+            data,
+        });
+        if self.entries.len() > MAX_SIDECAR_ENTRIES {
+            self.entries
+                .drain(..self.entries.len() - MAX_SIDECAR_ENTRIES);
+        }
+    }
+}
+
+/// Write a copy of box data that couldn't be read to a file in the given directory so it can be
+/// recovered later. If there's already an identical copy, that's used instead of writing another.
+pub fn write_damaged_copy(dir: &Path, buf: &[u8]) -> Result<PathBuf> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_copy = path.file_name().is_some_and(|name| {
+            let name = name.to_string_lossy();
+            name.starts_with("re0box_damaged_") && name.ends_with(".bin")
+        });
+        if is_copy && std::fs::read(&path).is_ok_and(|copy| copy == buf) {
+            return Ok(path);
+        }
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = dir.join(format!("re0box_damaged_{}.bin", timestamp));
+    std::fs::write(&path, buf)?;
+    Ok(path)
+}
+
+/// Find where our box data starts in a save file, given the size of the game's own save data for
+/// this game version. Returns None if the save has no box data we can read.
+///
+/// If the save isn't laid out the way we expect, we look for box data we can actually read before
+/// assuming anything, so that a game update that changes the size of the save doesn't cause us to
+/// treat the game's data as ours or vice versa.
+pub fn locate_box_data(buf: &[u8], save_size: usize) -> Option<usize> {
+    if buf.len() > save_size && buf[save_size..].starts_with(MAGIC) {
+        return Some(save_size);
+    }
+    if buf.len() == save_size {
+        return None;
+    }
+
+    let found = buf
+        .windows(MAGIC.len())
+        .enumerate()
+        .skip(SLOTS_SIZE)
+        .filter(|(_, window)| *window == MAGIC)
+        .map(|(offset, _)| offset)
+        .find(|&offset| SaveData::new().read(&buf[offset..]).is_ok());
+    match found {
+        Some(offset) => {
+            log::warn!(
+                "Expected box data to start at offset {} in the save file, but found it at offset {}. The game's save format may have changed.",
+                save_size,
+                offset
+            );
+            Some(offset)
+        }
+        None if buf.len() < save_size => {
+            log::warn!(
+                "Save file is {} bytes, which is shorter than the {} bytes expected for this game version. The game's save format may have changed.",
+                buf.len(),
+                save_size
+            );
+            None
+        }
+        None => {
+            // this is more likely to be the game's own data in a format we don't know than box
+            // data, so we leave it to the game
+            log::warn!(
+                "Save file has {} bytes after the game's save data that don't look like box data. The game's save format may have changed.",
+                buf.len() - save_size
+            );
+            None
+        }
+    }
+}
+
+/// What to do when a slot's box was saved with different slot data than the slot has now
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    /// Load the box anyway
+    #[default]
+    Keep,
+    /// Load an empty box
+    Empty,
+    /// Load the box that was saved with this slot's data, if any slot has one, or an empty box
+    /// otherwise
+    Search,
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "keep" => Self::Keep,
+            "empty" => Self::Empty,
+            "search" => Self::Search,
+            _ => bail!("Unknown mismatch policy {:?}", s),
+        })
+    }
+}
+
+/// What happens to a slot's box when the game is saved while the mod is disabled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisabledBehavior {
+    /// The box is deleted
+    #[default]
+    Clear,
+    /// The box is kept as it was, ready for when the mod is enabled again
+    Freeze,
+}
+
+impl FromStr for DisabledBehavior {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "clear" => Self::Clear,
+            "freeze" => Self::Freeze,
+            _ => bail!("Unknown disabled behavior {:?}", s),
+        })
+    }
+}
+
+/// Settings for how box data is saved and loaded
+#[derive(Debug, Default, Clone)]
+pub struct SaveSettings {
+    pub disabled: DisabledBehavior,
+    pub scope: BoxScope,
+    pub storage: Storage,
+    /// Number of backups to keep, or 0 to disable backups
+    pub backup_count: usize,
+    /// A backup to load instead of the box data in the save, the next time the game loads
+    pub restore: Option<String>,
+    pub mismatch: MismatchPolicy,
+    pub carryover: Carryover,
+    /// Key items beyond the ones in the catalog, which Carryover::NonKeyItems leaves behind
+    pub key_items: KeyItems,
+    pub starting_box: StartingBox,
+}
+
+/// Keeps track of the box data for all save slots and where it gets saved
+#[derive(Debug)]
+pub struct SaveManager {
+    is_mod_enabled: bool,
+    // the size of the game's own save data for the current game version
+    save_size: usize,
+    // the size of the game's own save data in the save file the game last loaded
+    vanilla_size: usize,
+    disabled: DisabledBehavior,
+    save_data: SaveData,
+    storage: Storage,
+    game_dir: PathBuf,
+    // whether the sidecar was unreadable when the game loaded, in which case we leave it alone
+    sidecar_damaged: bool,
+    // whether the save the game last loaded is bigger than we expect with no box data we can find,
+    // in which case we don't know where box data would go
+    unknown_save_format: bool,
+    backups: Backups,
+    restore: Option<String>,
+    last_saved_slot: usize,
+    // the slot most recently loaded or saved, which is where new games get their carried-over box
+    recent_slot: Option<usize>,
+    mismatch: MismatchPolicy,
+    carryover: Carryover,
+    key_items: KeyItems,
+    starting_box: StartingBox,
+    // fingerprints of the slots in the save file the game last loaded
+    fingerprints: Option<[u32; NUM_SAVE_SLOTS]>,
+    // the most recent box data that we know reads back correctly, to save instead if the current
+    // boxes don't
+    last_good: Option<Vec<u8>>,
+}
+
+impl SaveManager {
+    pub const fn new() -> Self {
+        Self {
+            is_mod_enabled: true,
+            save_size: UNMODDED_SAVE_SIZE,
+            vanilla_size: UNMODDED_SAVE_SIZE,
+            disabled: DisabledBehavior::Clear,
+            save_data: SaveData::new(),
+            storage: Storage::Trailer,
+            game_dir: PathBuf::new(),
+            sidecar_damaged: false,
+            unknown_save_format: false,
+            backups: Backups::new(PathBuf::new(), 0),
+            restore: None,
+            last_saved_slot: 0,
+            recent_slot: None,
+            mismatch: MismatchPolicy::Keep,
+            carryover: Carryover::Nothing,
+            key_items: KeyItems::new(),
+            starting_box: StartingBox::new(),
+            fingerprints: None,
+            last_good: None,
+        }
+    }
+
+    pub fn init(
+        &mut self,
+        is_mod_enabled: bool,
+        save_size: usize,
+        settings: SaveSettings,
+        game_dir: PathBuf,
+    ) {
+        self.is_mod_enabled = is_mod_enabled;
+        self.save_size = save_size;
+        self.vanilla_size = save_size;
+        self.disabled = settings.disabled;
+        self.save_data.set_scope(settings.scope);
+        self.storage = settings.storage;
+        self.backups = Backups::new(game_dir.join(BACKUP_DIR_NAME), settings.backup_count);
+        self.restore = settings.restore;
+        self.mismatch = settings.mismatch;
+        self.carryover = settings.carryover;
+        self.key_items = settings.key_items;
+        self.starting_box = settings.starting_box;
+        self.game_dir = game_dir;
+    }
+
+    pub fn save_to_slot(&mut self, items: &[Item], index: usize) {
+        self.last_saved_slot = index;
+        self.recent_slot = Some(index);
+        if self.is_mod_enabled {
+            self.save_data.save_slot(index, items);
+        } else {
+            match self.disabled {
+                DisabledBehavior::Clear => self.save_data.clear_slot(index),
+                DisabledBehavior::Freeze => self.save_data.freeze_slot(index),
+            }
+        }
+    }
+
+    pub fn load_from_slot(&mut self, index: usize) -> Vec<Item> {
+        self.recent_slot = Some(index);
+        if self.is_mod_enabled && self.save_data.is_frozen(index) {
+            log::info!(
+                "Slot {} was saved while the mod was disabled; its box is as it was before that",
+                index
+            );
+        }
+        // the shared box doesn't belong to any one slot, so it can't be mismatched, and emptying it
+        // would empty it for every save
+        if self.save_data.scope() == BoxScope::Global {
+            return self.save_data.load_slot(index);
+        }
+        let (Some(saved), Some(current)) = (
+            self.save_data.fingerprint(index),
+            self.fingerprints.map(|f| f[index]),
+        ) else {
+            // we can't tell whether the box belongs with this slot
+            return self.save_data.load_slot(index);
+        };
+        if saved == current {
+            return self.save_data.load_slot(index);
+        }
+
+        log::warn!(
+            "Slot {} has changed since its box was saved; the box may belong to a different save",
+            index
+        );
+        match self.mismatch {
+            MismatchPolicy::Keep => self.save_data.load_slot(index),
+            MismatchPolicy::Empty => self.save_data.load_empty_slot(index),
+            MismatchPolicy::Search => match self.save_data.find_fingerprint(current) {
+                Some(found) => {
+                    log::info!("Using the box saved with slot {} instead", found);
+                    self.save_data.load_slot(found)
+                }
+                None => {
+                    log::warn!("No box matches slot {}; using an empty box", index);
+                    self.save_data.load_empty_slot(index)
+                }
+            },
+        }
+    }
+
+    /// The box to start a new game with, which depends on the box scope
+    pub fn new_game_box(&mut self) -> Vec<Item> {
+        let mut items = self.save_data.new_game();
+        if self.save_data.scope() == BoxScope::Global {
+            // the shared box already carries everything over, and changing it here would change it
+            // for good the next time the game saves, so the starting items would pile up too
+            if self.carryover != Carryover::Nothing || !self.starting_box.items().is_empty() {
+                log::info!(
+                    "Not carrying over a box or adding starting items because the box is shared by every save"
+                );
+            }
+            return items;
+        }
+        if let Some(index) = self.recent_slot {
+            if self.carryover != Carryover::Nothing {
+                log::info!(
+                    "Carrying over box from slot {} ({:?})",
+                    index,
+                    self.carryover
+                );
+                items = self
+                    .carryover
+                    .apply(&self.save_data.peek_slot(index), &self.key_items);
+            }
+        }
+
+        items.extend_from_slice(self.starting_box.items());
+        items
+    }
+
+    fn sidecar_path(&self) -> PathBuf {
+        self.game_dir.join(SIDECAR_FILE_NAME)
+    }
+
+    /// Load the sidecar when the game loads. If it's unreadable, we set aside a copy and leave the
+    /// file alone, and the box can't be used until it can be read.
+    fn load_sidecar(&mut self) -> Result<Sidecar> {
+        let path = self.sidecar_path();
+        Sidecar::load(&path).inspect_err(|_| {
+            self.sidecar_damaged = true;
+            self.save_data.clear();
+            self.last_good = None;
+            if let Ok(buf) = std::fs::read(&path) {
+                match write_damaged_copy(&self.game_dir, &buf) {
+                    Ok(copy) => {
+                        log::warn!("Kept a copy of {} in {}", path.display(), copy.display())
+                    }
+                    Err(e) => log::error!("Failed to save a copy of {}: {:?}", path.display(), e),
+                }
+            }
+        })
+    }
+
+    /// Load the sidecar so we can add to it. If it's unreadable, we set aside a copy and start over
+    /// rather than refusing to save.
+    fn load_sidecar_for_update(&self) -> Sidecar {
+        let path = self.sidecar_path();
+        Sidecar::load(&path).unwrap_or_else(|e| {
+            log::error!("Failed to read {}: {:?}", path.display(), e);
+            if let Ok(buf) = std::fs::read(&path) {
+                match write_damaged_copy(&self.game_dir, &buf) {
+                    Ok(copy) => {
+                        log::warn!("Kept a copy of {} in {}", path.display(), copy.display())
+                    }
+                    Err(e) => log::error!("Failed to save a copy of {}: {:?}", path.display(), e),
+                }
+            }
+            Sidecar::default()
+        })
+    }
+
+    /// The save file to write: the game's save data, followed by our box data if it goes there
+    fn save_file(&self, game_buf: &[u8], box_buf: &[u8]) -> Vec<u8> {
+        let mut save_buf = Vec::with_capacity(game_buf.len() + box_buf.len());
+        save_buf.extend_from_slice(game_buf);
+        if self.storage == Storage::Trailer {
+            save_buf.extend_from_slice(box_buf);
+        }
+        save_buf
+    }
+
+    /// Check that loading the save file we're about to write will find the box data where we put
+    /// it
+    fn check_save_file(&self, game_size: usize, save_buf: &[u8]) -> Result<()> {
+        let box_offset = locate_box_data(save_buf, self.save_size);
+        let expected_offset = (self.storage == Storage::Trailer).then_some(game_size);
+        if box_offset != expected_offset {
+            bail!(
+                "Box data would be found at offset {:?} instead of {:?}",
+                box_offset,
+                expected_offset
+            );
+        }
+        Ok(())
+    }
+
+    /// Serialize the boxes, making sure the save file loads them back the same before they go
+    /// anywhere near it. If it doesn't, we fall back to the last box data we know is good. Also
+    /// returns whether the box data is the current boxes rather than older data.
+    fn encode_boxes(&mut self, game_buf: &[u8]) -> Result<(Vec<u8>, bool)> {
+        let box_buf = self.save_data.to_bytes()?;
+        if self.save_data.is_protected() {
+            return Ok((box_buf, false));
+        }
+        let save_buf = self.save_file(game_buf, &box_buf);
+        let result = self
+            .check_save_file(game_buf.len(), &save_buf)
+            .and_then(|_| self.save_data.verify(&box_buf));
+        if let Err(e) = result {
+            log::error!("Box data failed verification: {:?}", e);
+            // saving without box data would delete every box in the save, so if there's nothing
+            // good to save instead, the save has to fail
+            let Some(last_good) = self.last_good.clone() else {
+                bail!("No good box data to save instead");
+            };
+            self.check_save_file(game_buf.len(), &self.save_file(game_buf, &last_good))?;
+            log::warn!(
+                "Saving the last good box data instead; box changes since then won't be saved"
+            );
+            return Ok((last_good, false));
+        }
+        self.last_good = Some(box_buf.clone());
+        Ok((box_buf, true))
+    }
+
+    /// Encode the boxes, back them up, and write the save file with them. Returns the box data
+    /// that was written.
+    fn write_save_file(
+        &mut self,
+        game_buf: &[u8],
+        name: &CStr,
+        backend: &mut impl SaveBackend,
+    ) -> Result<Vec<u8>> {
+        let (box_buf, is_current) = self.encode_boxes(game_buf)?;
+        // anything other than the current boxes is already in the save or an earlier backup
+        if is_current && self.backups.is_enabled() {
+            // a failed backup shouldn't stop the save
+            match self.backups.write(self.last_saved_slot, &box_buf) {
+                Ok(path) => log::debug!("Backed up box data to {}", path.display()),
+                Err(e) => log::error!("Failed to back up box data: {:?}", e),
+            }
+        }
+        backend.write(name, &self.save_file(game_buf, &box_buf))?;
+        Ok(box_buf)
+    }
+
+    /// Save the game's save file, along with our box data, through the given backend
+    pub fn save(
+        &mut self,
+        game_buf: &[u8],
+        name: &CStr,
+        backend: &mut impl SaveBackend,
+    ) -> Result<()> {
+        if self.save_data.is_protected() {
+            log::warn!(
+                "Box data from the last load was unreadable; writing it back unchanged instead of the current boxes"
+            );
+        }
+        if self.sidecar_damaged {
+            log::warn!(
+                "{} was unreadable when the game loaded; saving without box data and leaving it as it is",
+                SIDECAR_FILE_NAME
+            );
+            return backend.write(name, game_buf);
+        }
+        if self.unknown_save_format {
+            log::warn!("The save file's format wasn't recognized when the game loaded; saving without box data");
+            return backend.write(name, game_buf);
+        }
+        let fingerprints = VanillaSave::parse(game_buf, game_buf.len())
+            .map(|save| save.fingerprints())
+            .inspect_err(|e| log::warn!("Can't fingerprint save slots: {:?}", e))
+            .ok();
+        // the box data has to carry the new fingerprints, but they don't describe anything until
+        // the save has actually been written
+        let previous = self.save_data.set_fingerprints(fingerprints);
+        let box_buf = match self.write_save_file(game_buf, name, backend) {
+            Ok(box_buf) => box_buf,
+            Err(e) => {
+                self.save_data.set_fingerprints(previous);
+                return Err(e);
+            }
+        };
+        // the save we've written is now the one the game has loaded
+        self.fingerprints = fingerprints;
+
+        if self.storage == Storage::Sidecar {
+            // the game's save stays vanilla and the box goes in our own file, but only once the
+            // save it goes with has actually been written
+            let mut sidecar = self.load_sidecar_for_update();
+            sidecar.set(crc32fast::hash(game_buf), box_buf);
+            sidecar.save(&self.sidecar_path())?;
+        }
+        Ok(())
+    }
+
+    fn restore_backup(&mut self, name: &str) -> Result<()> {
+        let data = self.backups.read(name)?;
+        self.save_data.read(&data)?;
+        self.last_good = Some(data);
+        // a backup is only restored once, even if the option is left set
+        if let Err(e) = self.backups.mark_restored(name) {
+            log::error!("Failed to mark backup {} as restored: {:?}", name, e);
+        }
+        Ok(())
+    }
+
+    /// Replace the boxes in damaged slots with the ones from the newest backup that has them intact
+    fn recover_slots(&mut self, damaged_slots: &[usize]) {
+        let paths = match self.backups.list() {
+            Ok(paths) => paths,
+            Err(e) => {
+                log::error!("Failed to list backups: {:?}", e);
+                return;
+            }
+        };
+
+        let mut remaining = damaged_slots.to_vec();
+        for path in paths.iter().rev() {
+            if remaining.is_empty() {
+                break;
+            }
+            let mut backup = SaveData::new();
+            let backup_damaged = match std::fs::read(path)
+                .map_err(Error::from)
+                .and_then(|data| backup.read(&data))
+            {
+                Ok(backup_damaged) => backup_damaged,
+                Err(e) => {
+                    log::warn!("Failed to read backup {}: {:?}", path.display(), e);
+                    continue;
+                }
+            };
+            remaining.retain(|&index| {
+                // don't take a box that was saved with different slot data than the slot has now
+                let matches = match (backup.fingerprint(index), self.fingerprints) {
+                    (Some(saved), Some(current)) => saved == current[index],
+                    _ => true,
+                };
+                if backup_damaged.contains(&index) || !matches {
+                    return true;
+                }
+                log::info!(
+                    "Recovered the box for slot {} from backup {}",
+                    index,
+                    path.display()
+                );
+                self.save_data.recover_slot(index, &backup);
+                false
+            });
+        }
+
+        for index in remaining {
+            log::warn!("No backup has an intact box for slot {}", index);
+        }
+    }
+
+    /// Whether we're holding on to unreadable box data, in which case box changes can't be saved
+    pub fn is_protected(&self) -> bool {
+        self.save_data.is_protected() || self.sidecar_damaged || self.unknown_save_format
+    }
+
+    /// The size of the game's own save data in the save file the game last loaded
+    pub fn vanilla_size(&self) -> usize {
+        self.vanilla_size
+    }
+
+    /// Load our box data from the game's save file, read through the given backend
+    pub fn load(&mut self, name: &CStr, backend: &mut impl SaveBackend) -> Result<()> {
+        let buf = backend.read(name)?;
+        self.load_buf(&buf)
+    }
+
+    fn load_buf(&mut self, buf: &[u8]) -> Result<()> {
+        self.sidecar_damaged = false;
+        let box_offset = locate_box_data(buf, self.save_size);
+        self.vanilla_size = box_offset.unwrap_or(buf.len());
+        self.unknown_save_format = box_offset.is_none() && buf.len() > self.save_size;
+        if self.unknown_save_format {
+            self.save_data.clear();
+            self.last_good = None;
+            bail!("No box data found in a save file of {} bytes; box saving is disabled for this save file", buf.len());
+        }
+        self.fingerprints = VanillaSave::parse(buf, self.vanilla_size)
+            .map(|save| save.fingerprints())
+            .ok();
+        if let Some(name) = self.restore.take() {
+            match self.restore_backup(&name) {
+                Err(_) if self.backups.is_restored(&name) => log::info!(
+                    "Backup {} was already restored; you can clear the Restore option",
+                    name
+                ),
+                Ok(()) => {
+                    log::info!("Restored box data from backup {}", name);
+                    return Ok(());
+                }
+                Err(e) => log::error!("Failed to restore backup {}: {:?}", name, e),
+            }
+        }
+
+        // box data is moved automatically to wherever the current storage setting says it goes the
+        // next time the game saves
+        let trailer = if let Some(offset) = box_offset {
+            if self.storage == Storage::Sidecar {
+                log::info!(
+                    "Moving box data from the save file to {}",
+                    SIDECAR_FILE_NAME
+                );
+            }
+            Some(Vec::from(&buf[offset..]))
+        } else {
+            let sidecar = self.load_sidecar()?;
+            let trailer = sidecar
+                .get(crc32fast::hash(buf))
+                .or_else(|| {
+                    let latest = sidecar.latest();
+                    if latest.is_some() {
+                        log::warn!(
+                            "{} has no box data saved with this save file, which may have been changed outside the game; using the most recently saved box data",
+                            SIDECAR_FILE_NAME
+                        );
+                    }
+                    latest
+                })
+                .map(Vec::from);
+            if trailer.is_some() && self.storage == Storage::Trailer {
+                log::info!(
+                    "Moving box data from {} to the save file",
+                    SIDECAR_FILE_NAME
+                );
+            }
+            trailer
+        };
+
+        let Some(trailer) = trailer else {
+            // this is the first time the mod has been used. clear out the boxes.
+            self.save_data.clear();
+            self.last_good = None;
+            return Ok(());
+        };
+
+        let damaged_slots = match self.save_data.read(&trailer) {
+            Ok(damaged_slots) => damaged_slots,
+            Err(e) => {
+                // keep the data we couldn't read both in the save and in a separate file so the
+                // player doesn't lose it
+                self.save_data.protect(&trailer);
+                match write_damaged_copy(&self.game_dir, &trailer) {
+                    Ok(path) => log::warn!(
+                        "Kept a copy of the unreadable box data in {}",
+                        path.display()
+                    ),
+                    Err(e) => {
+                        log::error!("Failed to save a copy of the unreadable box data: {:?}", e)
+                    }
+                }
+                return Err(e);
+            }
+        };
+        if !damaged_slots.is_empty() {
+            self.recover_slots(&damaged_slots);
+        }
+        self.last_good = Some(trailer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+
+    use super::*;
+    use crate::vanilla::SLOT_SIZE;
+
+    /// Where the first save slot starts in the supported game versions
+    const HEADER_SIZE: usize = UNMODDED_SAVE_SIZE - SLOTS_SIZE;
+
+    /// Writes save files to a folder on disk
+    #[derive(Debug)]
+    struct FileBackend {
+        dir: PathBuf,
+    }
+
+    impl FileBackend {
+        fn new(dir: PathBuf) -> Self {
+            Self { dir }
+        }
+
+        fn path(&self, name: &CStr) -> PathBuf {
+            self.dir.join(name.to_string_lossy().as_ref())
+        }
+    }
+
+    impl SaveBackend for FileBackend {
+        fn write(&mut self, name: &CStr, buf: &[u8]) -> Result<()> {
+            std::fs::create_dir_all(&self.dir)?;
+            std::fs::write(self.path(name), buf)?;
+            Ok(())
+        }
+
+        fn read(&mut self, name: &CStr) -> Result<Vec<u8>> {
+            Ok(std::fs::read(self.path(name))?)
+        }
+    }
+
+    /// Keeps save files in memory
+    #[derive(Debug, Default)]
+    struct MemoryBackend {
+        files: HashMap<CString, Vec<u8>>,
+    }
+
+    impl SaveBackend for MemoryBackend {
+        fn write(&mut self, name: &CStr, buf: &[u8]) -> Result<()> {
+            self.files.insert(CString::from(name), Vec::from(buf));
+            Ok(())
+        }
+
+        fn read(&mut self, name: &CStr) -> Result<Vec<u8>> {
+            match self.files.get(name) {
+                Some(buf) => Ok(buf.clone()),
+                None => bail!("No save file named {:?}", name),
+            }
+        }
+    }
+
+    fn items(ids: &[i32]) -> Vec<Item> {
+        ids.iter().map(|id| Item::new(*id, 1)).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("re0box-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manager(storage: Storage, game_dir: &Path) -> SaveManager {
+        let settings = SaveSettings {
+            storage,
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, game_dir.to_path_buf());
+        manager
+    }
+
+    const SAVE_NAME: &CStr = c"savedata.bin";
+
+    fn load(manager: &mut SaveManager, buf: &[u8]) -> Result<()> {
+        let mut backend = MemoryBackend::default();
+        backend.write(SAVE_NAME, buf)?;
+        manager.load(SAVE_NAME, &mut backend)
+    }
+
+    #[test]
+    fn trailer_round_trip() {
+        let dir = temp_dir("trailer");
+        let game_buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+
+        let mut manager = manager(Storage::Trailer, &dir);
+        manager.save_to_slot(&items(&[31]), 2);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        let buf = &backend.files[SAVE_NAME];
+        assert_eq!(&buf[..UNMODDED_SAVE_SIZE], game_buf.as_slice());
+        assert!(buf.len() > UNMODDED_SAVE_SIZE);
+
+        let mut manager = self::manager(Storage::Trailer, &dir);
+        manager.load(SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(2), items(&[31]));
+        // nothing was written outside the save
+        assert!(!dir.join(SIDECAR_FILE_NAME).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sidecar_round_trip() {
+        let dir = temp_dir("sidecar-storage");
+        let game_buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        let mut backend = FileBackend::new(dir.join("steam"));
+
+        // start with box data in the save file
+        let mut manager = manager(Storage::Trailer, &dir);
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+
+        // switching to the sidecar moves it out of the save file
+        let mut manager = self::manager(Storage::Sidecar, &dir);
+        manager.load(SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(0), items(&[31]));
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(backend.read(SAVE_NAME).unwrap(), game_buf);
+        assert!(dir.join(SIDECAR_FILE_NAME).exists());
+
+        let mut manager = self::manager(Storage::Sidecar, &dir);
+        manager.load(SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(0), items(&[31]));
+
+        // each save gets the box data that was saved with it
+        let other_buf = vec![0x5b; UNMODDED_SAVE_SIZE];
+        manager.save_to_slot(&items(&[32]), 0);
+        manager.save(&other_buf, SAVE_NAME, &mut backend).unwrap();
+        let mut manager = self::manager(Storage::Sidecar, &dir);
+        load(&mut manager, &game_buf).unwrap();
+        assert_eq!(manager.load_from_slot(0), items(&[31]));
+        load(&mut manager, &other_buf).unwrap();
+        assert_eq!(manager.load_from_slot(0), items(&[32]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_sidecar() {
+        let dir = temp_dir("unreadable-sidecar");
+        let game_buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+        let path = dir.join(SIDECAR_FILE_NAME);
+        std::fs::write(&path, b"IBSC\xff").unwrap();
+
+        let mut manager = manager(Storage::Sidecar, &dir);
+        assert!(load(&mut manager, &game_buf).is_err());
+        assert!(manager.is_protected());
+        // the sidecar is left alone and a copy is kept
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(backend.files[SAVE_NAME], game_buf);
+        assert_eq!(std::fs::read(&path).unwrap(), b"IBSC\xff");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locate_box_data_in_save() {
+        let dir = temp_dir("locate");
+        let mut backend = MemoryBackend::default();
+        let mut saver = manager(Storage::Trailer, &dir);
+        saver.save_to_slot(&items(&[31]), 0);
+        // pretend a game update made the header bigger
+        let game_buf = vec![0x5a; UNMODDED_SAVE_SIZE + 16];
+        saver.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        let buf = &backend.files[SAVE_NAME];
+
+        assert_eq!(locate_box_data(&game_buf, UNMODDED_SAVE_SIZE + 16), None);
+        assert_eq!(
+            locate_box_data(buf, UNMODDED_SAVE_SIZE + 16),
+            Some(UNMODDED_SAVE_SIZE + 16)
+        );
+        assert_eq!(
+            locate_box_data(buf, UNMODDED_SAVE_SIZE),
+            Some(UNMODDED_SAVE_SIZE + 16)
+        );
+        assert_eq!(
+            locate_box_data(&buf[..UNMODDED_SAVE_SIZE], UNMODDED_SAVE_SIZE + 16),
+            None
+        );
+        // extra data that isn't ours is left to the game
+        assert_eq!(locate_box_data(&game_buf, UNMODDED_SAVE_SIZE), None);
+
+        let mut manager = manager(Storage::Trailer, &dir);
+        load(&mut manager, buf).unwrap();
+        assert_eq!(manager.vanilla_size(), UNMODDED_SAVE_SIZE + 16);
+        assert_eq!(manager.load_from_slot(0), items(&[31]));
+
+        // and we don't save box data with it, since we don't know where it would go
+        let mut manager = self::manager(Storage::Trailer, &dir);
+        assert!(load(&mut manager, &game_buf).is_err());
+        assert_eq!(manager.vanilla_size(), game_buf.len());
+        assert!(manager.is_protected());
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(backend.files[SAVE_NAME], game_buf);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_box_data() {
+        let dir = temp_dir("unreadable");
+        let mut buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        buf.extend_from_slice(b"IBOX\xff\xff\xff\xff\x09");
+        let mut backend = MemoryBackend::default();
+
+        let mut manager = manager(Storage::Trailer, &dir);
+        assert!(load(&mut manager, &buf).is_err());
+        // the unreadable data is written back as-is
+        manager.save_to_slot(&items(&[31]), 0);
+        manager
+            .save(&buf[..UNMODDED_SAVE_SIZE], SAVE_NAME, &mut backend)
+            .unwrap();
+        assert_eq!(backend.files[SAVE_NAME], buf);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_mismatch_policy() {
+        assert_eq!(
+            "keep".parse::<MismatchPolicy>().unwrap(),
+            MismatchPolicy::Keep
+        );
+        assert_eq!(
+            "Empty".parse::<MismatchPolicy>().unwrap(),
+            MismatchPolicy::Empty
+        );
+        assert_eq!(
+            "search".parse::<MismatchPolicy>().unwrap(),
+            MismatchPolicy::Search
+        );
+        assert!("ignore".parse::<MismatchPolicy>().is_err());
+    }
+
+    #[test]
+    fn slot_mismatch() {
+        let dir = temp_dir("mismatch");
+        let mut game_buf = vec![0; UNMODDED_SAVE_SIZE];
+        let slot_offset = |index: usize| HEADER_SIZE + index * SLOT_SIZE;
+        game_buf[slot_offset(0)] = 1;
+        game_buf[slot_offset(1)] = 2;
+        let mut backend = MemoryBackend::default();
+
+        let mut manager = manager(Storage::Trailer, &dir);
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save_to_slot(&items(&[32]), 1);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+
+        // slot 0 is overwritten with a copy of slot 1 behind our back
+        let mut buf = backend.files[SAVE_NAME].clone();
+        buf[slot_offset(0)] = 2;
+
+        for (mismatch, expected) in [
+            (MismatchPolicy::Keep, items(&[31])),
+            (MismatchPolicy::Empty, vec![]),
+            (MismatchPolicy::Search, items(&[32])),
+        ] {
+            let settings = SaveSettings {
+                mismatch,
+                ..SaveSettings::default()
+            };
+            let mut manager = SaveManager::new();
+            manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+            load(&mut manager, &buf).unwrap();
+            assert_eq!(manager.load_from_slot(0), expected, "{:?}", mismatch);
+            // the unchanged slot is unaffected
+            assert_eq!(manager.load_from_slot(1), items(&[32]));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn global_mismatch() {
+        let dir = temp_dir("global-mismatch");
+        let mut game_buf = vec![0; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+        let settings = SaveSettings {
+            scope: BoxScope::Global,
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        game_buf[HEADER_SIZE] = 1;
+        let buf = [
+            &game_buf[..],
+            &backend.files[SAVE_NAME][UNMODDED_SAVE_SIZE..],
+        ]
+        .concat();
+
+        // the shared box doesn't belong to the slot, so it's loaded no matter what
+        for mismatch in [MismatchPolicy::Empty, MismatchPolicy::Search] {
+            let mut manager = SaveManager::new();
+            let settings = SaveSettings {
+                mismatch,
+                ..settings.clone()
+            };
+            manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+            load(&mut manager, &buf).unwrap();
+            assert_eq!(manager.load_from_slot(0), items(&[31]), "{:?}", mismatch);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn playthrough_mismatch() {
+        let dir = temp_dir("playthrough-mismatch");
+        let mut game_buf = vec![0; UNMODDED_SAVE_SIZE];
+        game_buf[HEADER_SIZE + SLOT_SIZE] = 2;
+        let mut backend = MemoryBackend::default();
+        let settings = SaveSettings {
+            scope: BoxScope::Playthrough,
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+        manager.new_game_box();
+        // both slots are from the same playthrough
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save_to_slot(&items(&[31, 32]), 1);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        game_buf[HEADER_SIZE] = 1;
+        let buf = [
+            &game_buf[..],
+            &backend.files[SAVE_NAME][UNMODDED_SAVE_SIZE..],
+        ]
+        .concat();
+
+        for mismatch in [MismatchPolicy::Empty, MismatchPolicy::Search] {
+            let mut manager = SaveManager::new();
+            let settings = SaveSettings {
+                mismatch,
+                ..settings.clone()
+            };
+            manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+            load(&mut manager, &buf).unwrap();
+            assert_eq!(
+                manager.load_from_slot(1),
+                items(&[31, 32]),
+                "{:?}",
+                mismatch
+            );
+            // the changed slot starts a new playthrough instead of emptying the one it was part of
+            assert_eq!(manager.load_from_slot(0), vec![], "{:?}", mismatch);
+            manager.save_to_slot(&items(&[40]), 0);
+            // saving the new playthrough leaves the old one alone
+            assert_eq!(
+                manager.load_from_slot(1),
+                items(&[31, 32]),
+                "{:?}",
+                mismatch
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_after_save() {
+        let dir = temp_dir("load-after-save");
+        let mut game_buf = vec![0; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+        let settings = SaveSettings {
+            mismatch: MismatchPolicy::Empty,
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        load(&mut manager, &game_buf).unwrap();
+
+        // saving changes the slot, but that's the slot the box was saved with
+        game_buf[HEADER_SIZE] = 1;
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(0), items(&[31]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disabled_behavior() {
+        let dir = temp_dir("disabled");
+        let game_buf = vec![0; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+        let mut manager = manager(Storage::Trailer, &dir);
+        manager.save_to_slot(&items(&[31]), 0);
+        manager.save_to_slot(&items(&[32]), 1);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+
+        for (disabled, expected) in [
+            (DisabledBehavior::Clear, vec![]),
+            (DisabledBehavior::Freeze, items(&[31])),
+        ] {
+            let settings = SaveSettings {
+                disabled,
+                ..SaveSettings::default()
+            };
+            // save slot 0 with the mod disabled
+            let mut manager = SaveManager::new();
+            manager.init(false, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+            manager.load(SAVE_NAME, &mut backend).unwrap();
+            manager.save_to_slot(&[], 0);
+            let mut disabled_backend = MemoryBackend::default();
+            manager
+                .save(&game_buf, SAVE_NAME, &mut disabled_backend)
+                .unwrap();
+
+            // and load it again with the mod enabled
+            let mut manager = SaveManager::new();
+            manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+            manager.load(SAVE_NAME, &mut disabled_backend).unwrap();
+            assert_eq!(manager.load_from_slot(0), expected, "{:?}", disabled);
+            assert_eq!(manager.load_from_slot(1), items(&[32]));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn carryover() {
+        let dir = temp_dir("carryover");
+        let settings = SaveSettings {
+            carryover: Carryover::Items(vec![31]),
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+        // nothing to carry over until we've loaded or saved something
+        assert!(manager.new_game_box().is_empty());
+
+        manager.save_to_slot(&items(&[31, 104]), 0);
+        manager.save_to_slot(&items(&[32, 104]), 1);
+        manager.load_from_slot(0);
+        assert_eq!(manager.new_game_box(), items(&[31]));
+
+        // nonkey leaves the hookshot behind, along with any key items from the config
+        let nonkey = SaveSettings {
+            carryover: Carryover::NonKeyItems,
+            key_items: "32".parse().unwrap(),
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, nonkey, dir.clone());
+        manager.save_to_slot(&items(&[31, 32, 104, 33]), 0);
+        assert_eq!(manager.new_game_box(), items(&[31, 33]));
+
+        // the shared box is left as it is
+        let settings = SaveSettings {
+            scope: BoxScope::Global,
+            ..settings
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        manager.save_to_slot(&items(&[31, 104]), 0);
+        assert_eq!(manager.new_game_box(), items(&[31, 104]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn starting_box() {
+        let dir = temp_dir("starting-box");
+        let settings = SaveSettings {
+            carryover: Carryover::Items(vec![32]),
+            starting_box: "6 x7, 31 x2".parse().unwrap(),
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        let starting_items = vec![Item::new(6, 7), Item::slot_two(), Item::new(31, 2)];
+        assert_eq!(manager.new_game_box(), starting_items);
+
+        // the starting items come after anything carried over
+        manager.save_to_slot(&items(&[32, 33]), 0);
+        let mut expected = items(&[32]);
+        expected.extend(starting_items);
+        assert_eq!(manager.new_game_box(), expected);
+
+        // but they aren't added to the shared box
+        let settings = SaveSettings {
+            scope: BoxScope::Global,
+            starting_box: "6 x7, 31 x2".parse().unwrap(),
+            ..SaveSettings::default()
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        assert!(manager.new_game_box().is_empty());
+        manager.save_to_slot(&items(&[33]), 0);
+        assert_eq!(manager.new_game_box(), items(&[33]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restore_backup() {
+        let dir = temp_dir("restore");
+        let game_buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+
+        let mut manager = SaveManager::new();
+        let settings = SaveSettings {
+            backup_count: 2,
+            ..SaveSettings::default()
+        };
+        manager.init(true, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+        manager.save_to_slot(&items(&[31]), 4);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+        manager.save_to_slot(&items(&[32]), 4);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+
+        let backups = Backups::new(dir.join(BACKUP_DIR_NAME), 2).list().unwrap();
+        assert_eq!(backups.len(), 2);
+        let name = backups[0].file_name().unwrap().to_str().unwrap();
+        // restoring doesn't need backups to be enabled
+        let settings = SaveSettings {
+            backup_count: 0,
+            restore: Some(String::from(name)),
+            ..settings
+        };
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+        manager.load(SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(4), items(&[31]));
+        // the backup is only restored once, even after restarting with the option still set
+        manager.load(SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(4), items(&[32]));
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        manager.load(SAVE_NAME, &mut backend).unwrap();
+        assert_eq!(manager.load_from_slot(4), items(&[32]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recover_damaged_slot() {
+        let dir = temp_dir("recover");
+        let game_buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        let mut backend = MemoryBackend::default();
+
+        let mut manager = SaveManager::new();
+        let settings = SaveSettings {
+            backup_count: 2,
+            ..SaveSettings::default()
+        };
+        manager.init(true, UNMODDED_SAVE_SIZE, settings.clone(), dir.clone());
+        manager.save_to_slot(&items(&[31]), 1);
+        manager.save_to_slot(&items(&[33]), 2);
+        manager.save(&game_buf, SAVE_NAME, &mut backend).unwrap();
+
+        // damage slot 2's box in the save, but not in the backup
+        let mut buf = backend.files[SAVE_NAME].clone();
+        let offset = buf.windows(4).position(|w| w == b"SLT\x02").unwrap();
+        buf[offset + 12] ^= 0xff;
+
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        load(&mut manager, &buf).unwrap();
+        assert_eq!(manager.load_from_slot(1), items(&[31]));
+        assert_eq!(manager.load_from_slot(2), items(&[33]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_verification() {
+        let dir = temp_dir("verification");
+        let mut backend = MemoryBackend::default();
+        let settings = SaveSettings {
+            backup_count: 2,
+            ..SaveSettings::default()
+        };
+        // game data that ends in something that looks like box data, so ours wouldn't be found
+        let mut other = SaveData::new();
+        other.save_slot(0, &items(&[99]));
+        let mut game_buf = vec![0x5a; UNMODDED_SAVE_SIZE];
+        game_buf.extend(other.to_bytes().unwrap());
+
+        let mut manager = SaveManager::new();
+        manager.init(true, UNMODDED_SAVE_SIZE, settings, dir.clone());
+        load(&mut manager, &vec![0x5a; UNMODDED_SAVE_SIZE]).unwrap();
+        let fingerprints = manager.fingerprints;
+        let saved_fingerprint = manager.save_data.fingerprint(0);
+        manager.save_to_slot(&items(&[31]), 0);
+        // with no good box data to fall back on, the save fails rather than dropping the boxes
+        assert!(manager.save(&game_buf, SAVE_NAME, &mut backend).is_err());
+        assert!(backend.files.is_empty());
+        // and the fingerprints still describe the save that was loaded, not the one we didn't write
+        assert_eq!(manager.fingerprints, fingerprints);
+        assert_eq!(manager.save_data.fingerprint(0), saved_fingerprint);
+        // and nothing unverified is backed up
+        assert!(!dir.join(BACKUP_DIR_NAME).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_copy() {
+        let dir = std::env::temp_dir().join(format!("re0box-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = write_damaged_copy(&dir, b"IBOX\xff").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"IBOX\xff");
+        // the same data isn't copied again
+        assert_eq!(write_damaged_copy(&dir, b"IBOX\xff").unwrap(), path);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_storage() {
+        assert_eq!("trailer".parse::<Storage>().unwrap(), Storage::Trailer);
+        assert_eq!("Sidecar".parse::<Storage>().unwrap(), Storage::Sidecar);
+        assert!("cloud".parse::<Storage>().is_err());
+    }
+
+    #[test]
+    fn sidecar() {
+        let dir = std::env::temp_dir().join(format!("re0box-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(SIDECAR_FILE_NAME);
+
+        let mut sidecar = Sidecar::load(&path).unwrap();
+        assert_eq!(sidecar, Sidecar::default());
+        assert_eq!(sidecar.latest(), None);
+
+        sidecar.set(1, vec![0xff; 8]);
+        sidecar.set(2, vec![1, 2, 3]);
+        sidecar.save(&path).unwrap();
+        let mut sidecar = Sidecar::load(&path).unwrap();
+        assert_eq!(sidecar.get(1), Some([0xff; 8].as_slice()));
+        assert_eq!(sidecar.get(3), None);
+        assert_eq!(sidecar.latest(), Some([1, 2, 3].as_slice()));
+        sidecar.set(1, vec![4]);
+        assert_eq!(sidecar.latest(), Some([4].as_slice()));
+        assert_eq!(sidecar.entries.len(), 2);
+        // only the most recent saves are kept
+        for checksum in 10..20 {
+            sidecar.set(checksum, vec![]);
+        }
+        assert_eq!(sidecar.entries.len(), MAX_SIDECAR_ENTRIES);
+        assert_eq!(sidecar.get(1), None);
+
+        std::fs::write(&path, b"IBOX").unwrap();
+        assert!(Sidecar::load(&path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{bail, Result};
+
+use super::save::NUM_SAVE_SLOTS;
+
+/// Size of each save slot. The game multiplies the slot index by this when loading and saving (see
+/// the load and save slot trampolines).
+pub const SLOT_SIZE: usize = 0x1c850;
+/// Size of all the save slots together. Whatever comes before them is a header we don't use.
+pub const SLOTS_SIZE: usize = NUM_SAVE_SLOTS * SLOT_SIZE;
+
+/// The save slots in the game's own save data
+///
+/// We only know where each slot is, not what's in it, so slots can only be told apart by their
+/// fingerprints.
+#[derive(Debug)]
+pub struct VanillaSave<'a> {
+    slots: [&'a [u8]; NUM_SAVE_SLOTS],
+}
+
+impl<'a> VanillaSave<'a> {
+    /// Parse the vanilla part of a save file, which is `size` bytes long. Anything after it (such
+    /// as our box data) is ignored.
+    pub fn parse(buf: &'a [u8], size: usize) -> Result<Self> {
+        if size < SLOTS_SIZE {
+            bail!(
+                "A save file of {} bytes is too short to hold {} save slots",
+                size,
+                NUM_SAVE_SLOTS
+            );
+        }
+        if buf.len() < size {
+            bail!(
+                "Save data is {} bytes, which is too short for a save file of {} bytes",
+                buf.len(),
+                size
+            );
+        }
+
+        let slot_buf = &buf[size - SLOTS_SIZE..size];
+        let mut slots = [&[] as &[u8]; NUM_SAVE_SLOTS];
+        for (slot, chunk) in slots.iter_mut().zip(slot_buf.chunks_exact(SLOT_SIZE)) {
+            *slot = chunk;
+        }
+
+        Ok(Self { slots })
+    }
+
+    /// A checksum of each slot's data, for telling whether a slot has changed
+    pub fn fingerprints(&self) -> [u32; NUM_SAVE_SLOTS] {
+        self.slots.map(crc32fast::hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::save::UNMODDED_SAVE_SIZE;
+
+    #[test]
+    fn layout() {
+        // the supported game versions have a 688-byte header before the slots
+        let header_size = UNMODDED_SAVE_SIZE - SLOTS_SIZE;
+        assert_eq!(header_size, 688);
+
+        let mut buf = vec![0xff; header_size];
+        for i in 0..NUM_SAVE_SLOTS {
+            buf.extend(std::iter::repeat_n(i as u8, SLOT_SIZE));
+        }
+        buf.extend_from_slice(b"IBOX");
+
+        let save = VanillaSave::parse(&buf, UNMODDED_SAVE_SIZE).unwrap();
+        let fingerprints = save.fingerprints();
+        for (i, fingerprint) in fingerprints.iter().enumerate() {
+            assert_eq!(*fingerprint, crc32fast::hash(&[i as u8; SLOT_SIZE]));
+        }
+
+        assert!(VanillaSave::parse(&buf[..UNMODDED_SAVE_SIZE - 1], UNMODDED_SAVE_SIZE).is_err());
+
+        // a bigger header doesn't move the slots relative to the end of the save
+        buf.insert(0, 0xff);
+        let save = VanillaSave::parse(&buf, UNMODDED_SAVE_SIZE + 1).unwrap();
+        assert_eq!(save.fingerprints(), fingerprints);
+        assert!(VanillaSave::parse(&buf, SLOTS_SIZE - 1).is_err());
+    }
+}